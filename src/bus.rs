@@ -0,0 +1,362 @@
+use anyhow::{anyhow, Context, Result};
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::{MatchRule, SignalArgs};
+use dbus::nonblock::stdintf::org_freedesktop_dbus::{
+    PropertiesPropertiesChanged, RequestNameReply,
+};
+use dbus::nonblock::SyncConnection;
+use dbus::Message;
+use dbus_crossroads::{Crossroads, IfaceToken, MethodErr};
+use dbus_tokio::connection;
+
+use tokio::task::JoinHandle;
+
+use log::*;
+
+use std::sync::Arc;
+
+use super::control::{Control, Request};
+use super::state::State;
+
+/// Both the bus name and the interface.
+const NAME: &str = "io.github.autovpn";
+const PATH: &str = "/io/github/autovpn";
+
+/// The values of the properties, compared to tell which ones changed.
+#[derive(PartialEq)]
+struct Properties {
+    state: String,
+    /// Empty if no wireless link is connected.
+    current_ssid: String,
+    trusted: bool,
+    /// Empty if the tunnel is down.
+    active_profile: String,
+}
+
+impl Properties {
+    fn new(control: &Control) -> Self {
+        let status = control.status();
+
+        Properties {
+            current_ssid: status
+                .links
+                .iter()
+                .filter(|l| l.wireless)
+                .find_map(|l| l.network.clone())
+                .unwrap_or_default(),
+            trusted: matches!(control.state(), State::Trusted),
+            active_profile: status.tunnel.map(|t| t.profile).unwrap_or_default(),
+            state: status.state,
+        }
+    }
+
+    fn changed(&self, old: &Properties) -> PropMap {
+        let mut changed = PropMap::new();
+        let mut insert = |name: &str, value: Box<dyn RefArg>| {
+            changed.insert(name.to_string(), Variant(value));
+        };
+
+        if self.state != old.state {
+            insert("State", Box::new(self.state.clone()));
+        }
+        if self.current_ssid != old.current_ssid {
+            insert("CurrentSsid", Box::new(self.current_ssid.clone()));
+        }
+        if self.trusted != old.trusted {
+            insert("Trusted", Box::new(self.trusted));
+        }
+        if self.active_profile != old.active_profile {
+            insert("ActiveProfile", Box::new(self.active_profile.clone()));
+        }
+
+        changed
+    }
+}
+
+fn call(control: &Control, request: Request) -> Result<(), MethodErr> {
+    control
+        .handle(request)
+        .map(|_| ())
+        .map_err(|e| MethodErr::failed(&format!("{:#}", e)))
+}
+
+fn register(cr: &mut Crossroads) -> IfaceToken<Arc<Control>> {
+    cr.register(NAME, |b| {
+        b.property("State")
+            .get(|_, control: &mut Arc<Control>| Ok(Properties::new(control).state));
+        b.property("CurrentSsid")
+            .get(|_, control: &mut Arc<Control>| Ok(Properties::new(control).current_ssid));
+        b.property("Trusted")
+            .get(|_, control: &mut Arc<Control>| Ok(Properties::new(control).trusted));
+        b.property("ActiveProfile")
+            .get(|_, control: &mut Arc<Control>| Ok(Properties::new(control).active_profile));
+
+        // An empty profile means the default one
+        b.method(
+            "Enable",
+            ("profile",),
+            (),
+            |_, control, (profile,): (String,)| {
+                let profile = (!profile.is_empty()).then_some(profile);
+                call(control, Request::Enable { profile })
+            },
+        );
+        b.method("Disable", (), (), |_, control, ()| {
+            call(control, Request::Disable)
+        });
+        b.method(
+            "Pause",
+            ("minutes",),
+            (),
+            |_, control, (minutes,): (u32,)| {
+                let minutes = minutes.into();
+                call(control, Request::Pause { minutes })
+            },
+        );
+        b.method("Resume", (), (), |_, control, ()| {
+            call(control, Request::Resume)
+        });
+        b.method("Reload", (), (), |_, control, ()| {
+            call(control, Request::Reload)
+        });
+
+        b.signal::<(String,), _>("StateChanged", ("state",));
+    })
+}
+
+/// Sends the changes of the properties and of the state whenever the state machine goes through
+/// a state, which also happens when only the links changed.
+async fn notify(conn: &SyncConnection, control: &Control) {
+    let path = dbus::Path::from(PATH);
+    let mut states = control.subscribe();
+    let mut last = Properties::new(control);
+
+    while states.changed().await.is_ok() {
+        let properties = Properties::new(control);
+
+        let changed = properties.changed(&last);
+        if !changed.is_empty() {
+            let signal = PropertiesPropertiesChanged {
+                interface_name: NAME.to_string(),
+                changed_properties: changed,
+                invalidated_properties: Vec::new(),
+            };
+            let _ = conn.send(signal.to_emit_message(&path));
+        }
+
+        if properties.state != last.state {
+            let signal = Message::signal(&path, &NAME.into(), &"StateChanged".into())
+                .append1(&properties.state);
+            let _ = conn.send(signal);
+        }
+
+        last = properties;
+    }
+}
+
+async fn serve(conn: Arc<SyncConnection>, control: Arc<Control>) -> Result<()> {
+    let reply = conn
+        .request_name(NAME, false, false, true)
+        .await
+        .with_context(|| format!("failed to own {}", NAME))?;
+    if reply != RequestNameReply::PrimaryOwner {
+        anyhow::bail!("{} is already owned", NAME);
+    }
+
+    let mut cr = Crossroads::new();
+    let token = register(&mut cr);
+    cr.insert(PATH, &[token], control.clone());
+
+    conn.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |msg, conn| {
+            if cr.handle_message(msg, conn).is_err() {
+                debug!("got a malformed dbus method call");
+            }
+            true
+        }),
+    );
+
+    notify(&conn, &control).await;
+    Ok(())
+}
+
+/// Owns [`NAME`] on the system bus, which is whatever `DBUS_SYSTEM_BUS_ADDRESS` points at if it is
+/// set. The daemon keeps running without it if the name can't be owned, e.g. without the policy.
+pub fn setup(control: Arc<Control>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let result = match connection::new_system_sync() {
+            Ok((resource, conn)) => tokio::select! {
+                err = resource => Err(anyhow!("lost system dbus connection: {}", err)),
+                result = serve(conn, control) => result,
+            },
+            Err(e) => Err(e.into()),
+        };
+
+        if let Err(e) = result {
+            error!("dbus service stopped: {:#}", e);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Event;
+    use crate::{config, links, Reloader};
+
+    use dbus::channel::Channel;
+    use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties as _;
+    use dbus::nonblock::Proxy;
+
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::process::{Child, Command};
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+    use tokio::sync::watch;
+    use tokio::time::{sleep, timeout, Duration};
+
+    use std::process::Stdio;
+
+    const CONFIG: &str = r#"
+wireguard_interface = "wg0"
+wlan_interfaces = []
+known_networks = []
+firewall_mark = 1
+routing_table = 2
+ipv6 = false
+"#;
+
+    /// Starts a private bus, returning it along with its address, or `None` without dbus-daemon.
+    async fn start_bus() -> Option<(Child, String)> {
+        let mut daemon = match Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+        {
+            Ok(daemon) => daemon,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => panic!("failed to start dbus-daemon: {}", e),
+        };
+
+        let stdout = daemon.stdout.take().unwrap();
+        let mut address = String::new();
+        BufReader::new(stdout)
+            .read_line(&mut address)
+            .await
+            .unwrap();
+        Some((daemon, address.trim().to_string()))
+    }
+
+    fn connect(address: &str) -> Arc<SyncConnection> {
+        let mut channel = Channel::open_private(address).unwrap();
+        channel.register().unwrap();
+        let (resource, conn) = connection::from_channel(channel).unwrap();
+        tokio::spawn(resource);
+        conn
+    }
+
+    fn control(state: watch::Receiver<State>) -> (Arc<Control>, UnboundedReceiver<Event>) {
+        let path = std::env::temp_dir().join(format!("autovpn-bus-{}.toml", std::process::id()));
+        std::fs::write(&path, CONFIG).unwrap();
+        let (config_tx, config_rx) = watch::channel(Arc::new(config::load(&path).unwrap()));
+
+        let (events, events_rx) = unbounded_channel();
+        std::fs::remove_file(&path).unwrap();
+        let reloader = Arc::new(Reloader { path, config_tx });
+        let control = Control::new(
+            events.clone(),
+            links::Links::new(events),
+            state,
+            config_rx,
+            reloader,
+        );
+        (control, events_rx)
+    }
+
+    #[tokio::test]
+    async fn serves_properties_methods_and_signals() {
+        let Some((_daemon, address)) = start_bus().await else {
+            eprintln!("dbus-daemon not found, skipping");
+            return;
+        };
+
+        let (state_tx, state_rx) = watch::channel(State::Disconnected);
+        let (control, mut events) = control(state_rx);
+        tokio::spawn(serve(connect(&address), control));
+
+        let client = connect(&address);
+        let proxy = Proxy::new(NAME, PATH, Duration::from_secs(2), client.clone());
+
+        // The service needs a moment to own its name
+        let mut state = None;
+        for _ in 0..50 {
+            if let Ok(s) = proxy.get::<String>(NAME, "State").await {
+                state = Some(s);
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(state.as_deref(), Some("disconnected"));
+        assert!(!proxy.get::<bool>(NAME, "Trusted").await.unwrap());
+        assert_eq!(
+            proxy.get::<String>(NAME, "ActiveProfile").await.unwrap(),
+            ""
+        );
+        assert_eq!(proxy.get::<String>(NAME, "CurrentSsid").await.unwrap(), "");
+
+        let () = proxy.method_call(NAME, "Pause", (30u32,)).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(Event::Pause(Some(_)))));
+        let () = proxy.method_call(NAME, "Resume", ()).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(Event::Resume)));
+        let error = proxy
+            .method_call::<(), _, _, _>(NAME, "Enable", ("missing",))
+            .await
+            .unwrap_err();
+        assert!(error
+            .message()
+            .unwrap()
+            .contains("no profile named 'missing'"));
+
+        // Both signals are sent when the state changes
+        let (tx, mut signals) = unbounded_channel();
+        let rule = MatchRule::new_signal(NAME, "StateChanged").with_path(PATH);
+        let t = tx.clone();
+        let state_changed =
+            client
+                .add_match(rule)
+                .await
+                .unwrap()
+                .cb(move |_, (state,): (String,)| {
+                    let _ = t.send(format!("StateChanged {}", state));
+                    true
+                });
+        let rule = PropertiesPropertiesChanged::match_rule(None, Some(&PATH.into())).static_clone();
+        let properties_changed = client.add_match(rule).await.unwrap().cb(
+            move |_, changed: PropertiesPropertiesChanged| {
+                let mut names: Vec<_> = changed.changed_properties.keys().cloned().collect();
+                names.sort();
+                let _ = tx.send(format!("PropertiesChanged {}", names.join(",")));
+                true
+            },
+        );
+
+        state_tx.send_replace(State::Trusted);
+        let mut got = Vec::new();
+        while got.len() < 2 {
+            let signal = timeout(Duration::from_secs(5), signals.recv()).await;
+            got.push(signal.expect("no signal").unwrap());
+        }
+        got.sort();
+        assert_eq!(
+            got,
+            ["PropertiesChanged State,Trusted", "StateChanged trusted"]
+        );
+        assert!(proxy.get::<bool>(NAME, "Trusted").await.unwrap());
+
+        drop((state_changed, properties_changed));
+    }
+}
@@ -0,0 +1,125 @@
+use anyhow::{Context, Result};
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+
+use super::control::{Interface, Request, Response, Status, SOCKET_PATH};
+
+/// Formats a byte count the way `wg` does, e.g. `1.50 MiB`.
+fn bytes(count: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = count as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} B", count),
+        _ => format!("{:.2} {}", value, units[unit]),
+    }
+}
+
+fn print_interface(interface: &Interface) {
+    println!(
+        "  listening on port {}, public key {}",
+        interface.listen_port,
+        interface.public_key.as_deref().unwrap_or("unset")
+    );
+    if interface.fwmark != 0 {
+        println!("  fwmark: {:#x}", interface.fwmark);
+    }
+
+    for peer in interface.peers.iter() {
+        println!("  peer {}", peer.public_key);
+        if let Some(endpoint) = peer.endpoint {
+            println!("    endpoint: {}", endpoint);
+        }
+        println!("    allowed ips: {}", peer.allowed_ips.join(", "));
+        match peer.last_handshake {
+            Some(secs) => println!("    latest handshake: {}m{}s ago", secs / 60, secs % 60),
+            None => println!("    latest handshake: never"),
+        }
+        println!(
+            "    transfer: {} received, {} sent",
+            bytes(peer.rx_bytes),
+            bytes(peer.tx_bytes)
+        );
+        if let Some(interval) = peer.persistent_keepalive {
+            println!("    keepalive: every {}s", interval);
+        }
+    }
+}
+
+/// Parses how long to pause for, such as `30m` or `2h`, with bare numbers taken as minutes.
+pub fn parse_minutes(s: &str) -> Result<u64, String> {
+    let (number, scale) = match s.strip_suffix('h') {
+        Some(hours) => (hours, 60),
+        None => (s.strip_suffix('m').unwrap_or(s), 1),
+    };
+
+    match number.parse::<u64>() {
+        Ok(n) if n > 0 => n
+            .checked_mul(scale)
+            .ok_or_else(|| format!("'{}' is too long", s)),
+        _ => Err(format!("'{}' is not a duration like 30m or 2h", s)),
+    }
+}
+
+fn print_status(status: &Status) {
+    println!("state: {}", status.state);
+
+    match &status.tunnel {
+        Some(tunnel) => println!(
+            "tunnel: profile '{}' on {}, routing table {}, dns {:?}, kill switch {}",
+            tunnel.profile,
+            tunnel.wireguard_interface,
+            tunnel.routing_table,
+            tunnel.dns,
+            if tunnel.kill_switch { "on" } else { "off" }
+        ),
+        None => println!("tunnel: down"),
+    }
+    if let Some(interface) = status.tunnel.as_ref().and_then(|t| t.interface.as_ref()) {
+        print_interface(interface);
+    }
+
+    for link in status.links.iter() {
+        let name = link
+            .ifname
+            .clone()
+            .unwrap_or_else(|| format!("link {}", link.ifindex));
+        let network = link
+            .network
+            .as_ref()
+            .map_or_else(|| String::from("unknown network"), |n| format!("'{}'", n));
+        match &link.decision {
+            Some(decision) if *decision != link.verdict => {
+                println!("{}: {} is {} ({})", name, network, link.verdict, decision)
+            }
+            _ => println!("{}: {} is {}", name, network, link.verdict),
+        }
+    }
+}
+
+/// Sends `request` to the running daemon and prints the answer.
+pub fn run(request: Request) -> Result<()> {
+    let mut stream = UnixStream::connect(SOCKET_PATH)
+        .with_context(|| format!("unable to connect to {}, is autovpn running?", SOCKET_PATH))?;
+
+    let mut line = serde_json::to_vec(&request)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+
+    match serde_json::from_str(&reply).context("malformed reply from autovpn")? {
+        Response::Ok(message) => println!("{}", message),
+        Response::Status(status) => print_status(&status),
+        Response::Error(e) => anyhow::bail!(e),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,942 @@
+use super::matcher::{self, SsidMatcher};
+use super::metrics;
+use super::portal::HttpUrl;
+use super::security::Security;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A hardware address, written in config files as `aa:bb:cc:dd:ee:ff`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok().map(MacAddr)
+    }
+}
+
+impl TryFrom<String> for MacAddr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let mut mac = [0u8; 6];
+        let mut parts = s.split(':');
+
+        for byte in mac.iter_mut() {
+            let part = parts
+                .next()
+                .ok_or_else(|| format!("mac address '{}' is too short", s))?;
+            *byte = u8::from_str_radix(part, 16)
+                .map_err(|_| format!("invalid byte '{}' in mac address '{}'", part, s))?;
+        }
+
+        if parts.next().is_some() {
+            return Err(format!("mac address '{}' is too long", s));
+        }
+
+        Ok(MacAddr(mac))
+    }
+}
+
+impl From<MacAddr> for String {
+    fn from(mac: MacAddr) -> Self {
+        mac.to_string()
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+#[derive(Deserialize)]
+struct KnownNetworkTable {
+    ssid: String,
+    #[serde(default)]
+    bssids: Vec<MacAddr>,
+    #[serde(default)]
+    ignore_case: bool,
+    #[serde(default)]
+    gateway_macs: Vec<MacAddr>,
+    #[serde(default)]
+    learn: bool,
+    #[serde(default)]
+    security: Vec<Security>,
+    eap_identity: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawKnownNetwork {
+    Ssid(String),
+    Table(KnownNetworkTable),
+}
+
+/// The default gateways a known network is expected to have.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GatewayFingerprint {
+    pub gateway_macs: Vec<MacAddr>,
+    /// Record the gateway the first time the network is used if none are known.
+    pub learn: bool,
+}
+
+/// An entry in `known_networks`, either a bare SSID pattern or a table that can also match case
+/// insensitively, pin the access points the network is allowed to be served from, require a
+/// security type or EAP identity and check its default gateway.
+#[derive(Deserialize)]
+#[serde(try_from = "RawKnownNetwork")]
+pub struct KnownNetwork {
+    ssid: SsidMatcher,
+    bssids: Vec<MacAddr>,
+    security: Vec<Security>,
+    eap_identity: Option<String>,
+    gateway: Option<GatewayFingerprint>,
+}
+
+impl TryFrom<RawKnownNetwork> for KnownNetwork {
+    type Error = String;
+
+    fn try_from(raw: RawKnownNetwork) -> Result<Self, Self::Error> {
+        let table = match raw {
+            RawKnownNetwork::Ssid(ssid) => KnownNetworkTable {
+                ssid,
+                bssids: Vec::new(),
+                ignore_case: false,
+                gateway_macs: Vec::new(),
+                learn: false,
+                security: Vec::new(),
+                eap_identity: None,
+            },
+            RawKnownNetwork::Table(table) => table,
+        };
+
+        let gateway =
+            (!table.gateway_macs.is_empty() || table.learn).then_some(GatewayFingerprint {
+                gateway_macs: table.gateway_macs,
+                learn: table.learn,
+            });
+
+        Ok(KnownNetwork {
+            ssid: SsidMatcher::new(&table.ssid, table.ignore_case)?,
+            bssids: table.bssids,
+            security: table.security,
+            eap_identity: table.eap_identity,
+            gateway,
+        })
+    }
+}
+
+impl KnownNetwork {
+    fn allows_bssid(&self, bssid: Option<MacAddr>) -> bool {
+        self.bssids.is_empty() || bssid.is_some_and(|b| self.bssids.contains(&b))
+    }
+
+    fn allows_security(&self, security: Security, eap_identity: Option<&str>) -> bool {
+        let security_matches = self.security.is_empty() || self.security.contains(&security);
+        let identity_matches =
+            self.eap_identity.is_none() || self.eap_identity.as_deref() == eap_identity;
+
+        security_matches && identity_matches
+    }
+}
+
+/// Whether a network is trusted, along with why when it isn't obvious.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Trust {
+    Trusted,
+    Untrusted,
+    /// The SSID is known but the network is open or WEP, which is never trusted.
+    Insecure,
+    /// The SSID is known but the access point is not one of the pinned BSSIDs.
+    UnexpectedBssid,
+    /// The SSID is known but the security type or EAP identity is not the expected one.
+    UnexpectedSecurity,
+    /// The SSID is known, but only trusted once the default gateway matches.
+    VerifyGateway(GatewayFingerprint),
+}
+
+impl fmt::Display for Trust {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Trust::Trusted => "trusted",
+            Trust::Untrusted => "untrusted",
+            Trust::Insecure => "insecure",
+            Trust::UnexpectedBssid => "unexpected-bssid",
+            Trust::UnexpectedSecurity => "unexpected-security",
+            Trust::VerifyGateway(_) => "verify-gateway",
+        })
+    }
+}
+
+/// How DNS is handled on the WireGuard interface while a profile is active.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dns {
+    /// Route every query through the tunnel.
+    #[default]
+    Tunnel,
+    /// Leave the link DNS settings alone.
+    System,
+    /// Only route queries for these domains through the tunnel.
+    Domains(Vec<String>),
+}
+
+/// The tunnel that is brought up while on an untrusted network. Profiles are compared by value,
+/// since reloading the config creates new ones even if nothing changed.
+#[derive(Debug, Deserialize, Eq, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    #[serde(default)]
+    networks: Vec<SsidMatcher>,
+    pub wireguard_interface: String,
+    pub firewall_mark: u32,
+    pub routing_table: u32,
+    #[serde(default)]
+    pub dns: Dns,
+}
+
+/// A trusted wired network, recognised by its gateway and/or the domain handed out by DHCP. Every
+/// criterion that is given has to match.
+#[derive(Debug, Deserialize)]
+pub struct WiredNetwork {
+    pub name: String,
+    #[serde(default)]
+    gateway_macs: Vec<MacAddr>,
+    #[serde(default)]
+    domains: Vec<String>,
+}
+
+impl WiredNetwork {
+    fn matches(&self, gateway_mac: Option<MacAddr>, domain: Option<&str>) -> bool {
+        let gateway_matches = self.gateway_macs.is_empty()
+            || gateway_mac.is_some_and(|m| self.gateway_macs.contains(&m));
+        let domain_matches = self.domains.is_empty()
+            || domain.is_some_and(|d| self.domains.iter().any(|s| s.eq_ignore_ascii_case(d)));
+
+        gateway_matches && domain_matches
+    }
+}
+
+fn default_portal_status() -> u16 {
+    200
+}
+
+fn default_portal_interval() -> u64 {
+    5
+}
+
+/// The probe used to tell whether an untrusted network is behind a captive portal. The tunnel is
+/// held back until the probe gets the expected response, so the portal can be logged into.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CaptivePortal {
+    pub url: HttpUrl,
+    #[serde(default = "default_portal_status")]
+    pub expected_status: u16,
+    /// Compared with surrounding whitespace removed, any body is accepted if unset.
+    pub expected_body: Option<String>,
+    /// Seconds between probes while behind a portal.
+    #[serde(default = "default_portal_interval")]
+    pub interval: u64,
+}
+
+fn default_reconcile_interval() -> u64 {
+    30
+}
+
+fn default_shutdown_timeout() -> u64 {
+    10
+}
+
+fn default_hook_timeout() -> u64 {
+    30
+}
+
+fn default_handshake_timeout() -> u64 {
+    30
+}
+
+fn default_port_attempts() -> u32 {
+    5
+}
+
+/// Shell commands run around turning the tunnel on and off, and when it stops or starts getting
+/// handshakes, see [`super::hooks::Hooks`].
+#[derive(Clone, Debug, Deserialize)]
+pub struct Hooks {
+    pub pre_enable: Option<String>,
+    pub post_enable: Option<String>,
+    pub pre_disable: Option<String>,
+    pub post_disable: Option<String>,
+    pub unhealthy: Option<String>,
+    /// Only run when an unhealthy tunnel recovers.
+    pub healthy: Option<String>,
+    /// Seconds after which a hook is killed and counted as failed.
+    #[serde(default = "default_hook_timeout")]
+    pub timeout: u64,
+    /// Whether a failing pre hook stops the tunnel from being turned on or off.
+    #[serde(default)]
+    pub veto: bool,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Hooks {
+            pre_enable: None,
+            post_enable: None,
+            pre_disable: None,
+            post_disable: None,
+            unhealthy: None,
+            healthy: None,
+            timeout: default_hook_timeout(),
+            veto: false,
+        }
+    }
+}
+
+/// Watching for handshakes once the tunnel is up. Without one in time the listen port is
+/// changed, waiting twice as long after each change, and the tunnel is reported as unhealthy.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Health {
+    /// Seconds to wait for a handshake before changing the listen port, 0 disables the checks.
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout: u64,
+    /// How often the listen port is changed before giving up until the tunnel is turned on again.
+    #[serde(default = "default_port_attempts")]
+    pub max_attempts: u32,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            handshake_timeout: default_handshake_timeout(),
+            max_attempts: default_port_attempts(),
+        }
+    }
+}
+
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(v) => v,
+    })
+}
+
+#[derive(Deserialize)]
+struct RawConfig {
+    wireguard_interface: String,
+    /// Either a list of interface names or a single name, `*` monitors every wireless interface.
+    #[serde(alias = "wlan_interface", deserialize_with = "one_or_many")]
+    wlan_interfaces: Vec<String>,
+    #[serde(default)]
+    known_networks: Vec<KnownNetwork>,
+    firewall_mark: u32,
+    routing_table: u32,
+    #[serde(default)]
+    dns: Dns,
+    ipv6: bool,
+    #[serde(default, rename = "profile")]
+    profiles: Vec<Profile>,
+    /// Like `wlan_interfaces`, `*` monitors every wired ethernet interface.
+    #[serde(default, deserialize_with = "one_or_many")]
+    wired_interfaces: Vec<String>,
+    #[serde(default, rename = "wired_network")]
+    wired_networks: Vec<WiredNetwork>,
+    captive_portal: Option<CaptivePortal>,
+    #[serde(default = "default_reconcile_interval")]
+    reconcile_interval: u64,
+    #[serde(default)]
+    kill_switch: bool,
+    control_group: Option<String>,
+    metrics_address: Option<metrics::Address>,
+    #[serde(default)]
+    hooks: Hooks,
+    #[serde(default)]
+    notifications: bool,
+    #[serde(default = "default_shutdown_timeout")]
+    shutdown_timeout: u64,
+    #[serde(default)]
+    health: Health,
+}
+
+#[derive(Deserialize)]
+#[serde(try_from = "RawConfig")]
+pub struct Config {
+    pub wlan_interfaces: Vec<String>,
+    pub known_networks: Vec<KnownNetwork>,
+    pub ipv6: bool,
+    /// Built from the top level settings, used when no `[[profile]]` matches the network.
+    pub default_profile: Arc<Profile>,
+    pub profiles: Vec<Arc<Profile>>,
+    pub wired_interfaces: Vec<String>,
+    pub wired_networks: Vec<WiredNetwork>,
+    /// Without it the tunnel is enabled on untrusted networks right away.
+    pub captive_portal: Option<CaptivePortal>,
+    /// Seconds between checks that the rules and DNS are still as they were set, 0 disables them.
+    pub reconcile_interval: u64,
+    /// Drop traffic that doesn't go through the tunnel while on an untrusted network.
+    pub kill_switch: bool,
+    /// Members may use the control socket besides root. Only read on startup.
+    pub control_group: Option<String>,
+    /// Where to serve the metrics, which are off without it. Only read on startup.
+    pub metrics_address: Option<metrics::Address>,
+    pub hooks: Hooks,
+    /// Tell the logged in user when the VPN is turned on for an unknown network.
+    pub notifications: bool,
+    /// Seconds to tear everything down in when stopping, after which the daemon gives up on it.
+    pub shutdown_timeout: u64,
+    pub health: Health,
+}
+
+impl TryFrom<RawConfig> for Config {
+    type Error = String;
+
+    fn try_from(raw: RawConfig) -> Result<Self, Self::Error> {
+        let default_profile = Profile {
+            name: String::from("default"),
+            networks: Vec::new(),
+            wireguard_interface: raw.wireguard_interface,
+            firewall_mark: raw.firewall_mark,
+            routing_table: raw.routing_table,
+            dns: raw.dns,
+        };
+
+        let mut names = vec![default_profile.name.as_str()];
+        for profile in raw.profiles.iter() {
+            if names.contains(&profile.name.as_str()) {
+                return Err(format!("duplicate profile '{}'", profile.name));
+            }
+            names.push(&profile.name);
+        }
+
+        Ok(Config {
+            wlan_interfaces: raw.wlan_interfaces,
+            known_networks: raw.known_networks,
+            ipv6: raw.ipv6,
+            default_profile: Arc::new(default_profile),
+            profiles: raw.profiles.into_iter().map(Arc::new).collect(),
+            wired_interfaces: raw.wired_interfaces,
+            wired_networks: raw.wired_networks,
+            captive_portal: raw.captive_portal,
+            reconcile_interval: raw.reconcile_interval,
+            kill_switch: raw.kill_switch,
+            control_group: raw.control_group,
+            metrics_address: raw.metrics_address,
+            hooks: raw.hooks,
+            notifications: raw.notifications,
+            shutdown_timeout: raw.shutdown_timeout,
+            health: raw.health,
+        })
+    }
+}
+
+impl Config {
+    pub fn trust(
+        &self,
+        ssid: &str,
+        bssid: Option<MacAddr>,
+        security: Security,
+        eap_identity: Option<&str>,
+    ) -> Trust {
+        let mut matches = self
+            .known_networks
+            .iter()
+            .filter(|n| n.ssid.is_match(ssid))
+            .peekable();
+
+        if matches.peek().is_none() {
+            return Trust::Untrusted;
+        }
+
+        // Anyone can set up an open network with the same name, whatever the config says
+        if security == Security::Open {
+            return Trust::Insecure;
+        }
+
+        let mut matches = matches.filter(|n| n.allows_bssid(bssid)).peekable();
+        if matches.peek().is_none() {
+            return Trust::UnexpectedBssid;
+        }
+
+        match matches.find(|n| n.allows_security(security, eap_identity)) {
+            Some(KnownNetwork {
+                gateway: Some(fingerprint),
+                ..
+            }) => Trust::VerifyGateway(fingerprint.clone()),
+            Some(_) => Trust::Trusted,
+            None => Trust::UnexpectedSecurity,
+        }
+    }
+
+    /// Whether any entry of `known_networks` matches `ssid`.
+    pub fn knows(&self, ssid: &str) -> bool {
+        self.known_networks.iter().any(|n| n.ssid.is_match(ssid))
+    }
+
+    /// Whether trusting `ssid` depends on the EAP identity, which is costly to look up.
+    pub fn needs_eap_identity(&self, ssid: &str) -> bool {
+        self.known_networks
+            .iter()
+            .any(|n| n.eap_identity.is_some() && n.ssid.is_match(ssid))
+    }
+
+    /// Picks the first profile with a network matching `ssid`, falling back to the default.
+    pub fn profile_for(&self, ssid: &str) -> Arc<Profile> {
+        self.profiles
+            .iter()
+            .find(|p| p.networks.iter().any(|n| n.is_match(ssid)))
+            .unwrap_or(&self.default_profile)
+            .clone()
+    }
+
+    pub fn monitors(&self, ifname: &str) -> bool {
+        self.wlan_interfaces.iter().any(|i| i == "*" || i == ifname)
+    }
+
+    pub fn monitors_wired(&self, ifname: &str) -> bool {
+        self.wired_interfaces
+            .iter()
+            .any(|i| i == "*" || i == ifname)
+    }
+
+    /// Finds the trusted wired network with this gateway and DHCP domain.
+    pub fn wired_network(
+        &self,
+        gateway_mac: Option<MacAddr>,
+        domain: Option<&str>,
+    ) -> Option<&WiredNetwork> {
+        self.wired_networks
+            .iter()
+            .find(|n| n.matches(gateway_mac, domain))
+    }
+
+    /// Checks the values that parse fine but can't work.
+    pub fn validate(&self) -> Result<()> {
+        for profile in self.all_profiles() {
+            if profile.firewall_mark == 0 {
+                anyhow::bail!("profile '{}': firewall_mark must not be 0", profile.name);
+            }
+
+            // 0 is unspecified and 253 to 255 are the default, main and local tables
+            if matches!(profile.routing_table, 0 | 253..=255) {
+                anyhow::bail!(
+                    "profile '{}': routing_table {} is reserved",
+                    profile.name,
+                    profile.routing_table
+                );
+            }
+        }
+
+        for network in self.known_networks.iter() {
+            if network.security.contains(&Security::Open) {
+                anyhow::bail!(
+                    "known network '{}': open and WEP networks are never trusted",
+                    network.ssid
+                );
+            }
+        }
+
+        if self
+            .captive_portal
+            .as_ref()
+            .is_some_and(|p| p.interval == 0)
+        {
+            anyhow::bail!("captive_portal: interval must not be 0");
+        }
+
+        for network in self.wired_networks.iter() {
+            if network.gateway_macs.is_empty() && network.domains.is_empty() {
+                anyhow::bail!(
+                    "wired network '{}' needs gateway_macs or domains",
+                    network.name
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the configured interfaces that don't exist right now. This isn't part of
+    /// [`Config::validate`] since interfaces may come and go while running.
+    pub fn missing_interfaces(&self) -> Vec<&str> {
+        self.wlan_interfaces
+            .iter()
+            .chain(self.wired_interfaces.iter())
+            .map(String::as_str)
+            .filter(|i| *i != "*")
+            .chain(self.all_profiles().map(|p| p.wireguard_interface.as_str()))
+            .filter(|i| !Path::new("/sys/class/net").join(i).exists())
+            .collect()
+    }
+
+    pub fn profile_named(&self, name: &str) -> Option<&Arc<Profile>> {
+        self.all_profiles().find(|p| p.name == name)
+    }
+
+    /// Every profile, used to clean up when it is unknown which one was last applied.
+    pub fn all_profiles(&self) -> impl Iterator<Item = &Arc<Profile>> {
+        std::iter::once(&self.default_profile).chain(self.profiles.iter())
+    }
+}
+
+fn parse(text: &str) -> Result<Config> {
+    let config = toml::from_str::<Config>(text).context("invalid config.toml")?;
+    config.validate().context("invalid config.toml")?;
+    Ok(config)
+}
+
+/// Reads, parses and validates the config at `path`.
+pub fn load(path: &Path) -> Result<Config> {
+    let c = std::fs::read(path)
+        .with_context(|| format!("unable to read config at {}", path.display()))?;
+    parse(&String::from_utf8_lossy(&c))
+}
+
+/// Applies `f` to the config at `path`, keeping comments and formatting. Nothing is written unless
+/// the result is still a valid config.
+fn edit(path: &Path, f: impl FnOnce(&mut toml_edit::Document) -> Result<()>) -> Result<()> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("unable to read config at {}", path.display()))?;
+    let mut document: toml_edit::Document = text.parse().context("invalid config.toml")?;
+    f(&mut document)?;
+
+    let text = document.to_string();
+    parse(&text)?;
+
+    // Written next to it and moved over, so that a crash can't leave half a config behind
+    let new = path.with_extension("toml.new");
+    std::fs::write(&new, text).with_context(|| format!("unable to write {}", new.display()))?;
+    std::fs::set_permissions(&new, std::fs::metadata(path)?.permissions())?;
+    std::fs::rename(&new, path).with_context(|| format!("unable to replace {}", path.display()))?;
+    Ok(())
+}
+
+/// Adds `ssid` to `known_networks` as an entry that only matches exactly that SSID, see
+/// [`matcher::literal`].
+pub fn add_known_network(path: &Path, ssid: &str) -> Result<()> {
+    let entry = matcher::literal(ssid);
+    edit(path, |document| {
+        let networks = document
+            .entry("known_networks")
+            .or_insert(toml_edit::value(toml_edit::Array::new()));
+
+        if let Some(array) = networks.as_array_mut() {
+            // Lined up with the entry before it, which matters in a multi-line list
+            let mut value = toml_edit::Value::from(entry.as_str());
+            let prefix = array.iter().last().and_then(|v| v.decor().prefix());
+            if let Some(prefix) = prefix.and_then(|p| p.as_str()) {
+                let indent = prefix.rfind('\n').map_or(" ", |i| &prefix[i..]);
+                value.decor_mut().set_prefix(indent.to_string());
+            }
+            array.push_formatted(value);
+        } else if let Some(tables) = networks.as_array_of_tables_mut() {
+            let mut table = toml_edit::Table::new();
+            table.insert("ssid", toml_edit::value(entry.as_str()));
+            tables.push(table);
+        } else {
+            anyhow::bail!("known_networks is not a list");
+        }
+        Ok(())
+    })
+}
+
+/// Removes every entry of `known_networks` for exactly `ssid`, as written by
+/// [`add_known_network`], returning how many there were. Patterns that happen to match `ssid` are
+/// left alone.
+pub fn remove_known_network(path: &Path, ssid: &str) -> Result<usize> {
+    let entry = matcher::literal(ssid);
+    let mut removed = 0;
+    edit(path, |document| {
+        let networks = document
+            .get_mut("known_networks")
+            .context("no known_networks in config")?;
+
+        if let Some(array) = networks.as_array_mut() {
+            let before = array.len();
+            array.retain(|v| {
+                let value = v.as_inline_table().and_then(|t| t.get("ssid")).unwrap_or(v);
+                value.as_str() != Some(entry.as_str())
+            });
+            removed = before - array.len();
+
+            // Whatever comes first now keeps the space it had after a comma
+            if let Some(first) = array.get_mut(0) {
+                let prefix = first.decor().prefix().and_then(|p| p.as_str());
+                if prefix.is_some_and(|p| !p.contains('\n')) {
+                    first.decor_mut().set_prefix("");
+                }
+            }
+        } else if let Some(tables) = networks.as_array_of_tables_mut() {
+            let before = tables.len();
+            tables.retain(|t| t.get("ssid").and_then(|s| s.as_str()) != Some(entry.as_str()));
+            removed = before - tables.len();
+        }
+
+        if removed == 0 {
+            anyhow::bail!("'{}' is not in known_networks", ssid);
+        }
+        Ok(())
+    })?;
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"wireguard_interface = "wg0"
+wlan_interfaces = []
+firewall_mark = 1
+routing_table = 2
+ipv6 = false
+"#;
+
+    /// Writes `CONFIG` with `extra` added to a temporary file, applies `f` to it and returns what
+    /// the file ends up as.
+    fn edited(name: &str, extra: &str, f: impl FnOnce(&Path) -> Result<()>) -> Result<String> {
+        let path = std::env::temp_dir().join(format!(
+            "autovpn-config-{}-{}.toml",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, format!("{}{}", CONFIG, extra)).unwrap();
+        let result = f(&path).and_then(|()| Ok(std::fs::read_to_string(&path)?));
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn adds_to_an_inline_list() {
+        let text = edited("add-inline", "known_networks = [\"Home\"]\n", |path| {
+            add_known_network(path, "Cafe")
+        })
+        .unwrap();
+        assert!(text.ends_with("known_networks = [\"Home\", \"Cafe\"]\n"));
+    }
+
+    #[test]
+    fn adds_to_a_multi_line_list() {
+        let list = "known_networks = [\n    \"Home\",\n    # the office\n    \"Work\",\n]\n";
+        let text = edited("add-lines", list, |path| add_known_network(path, "Cafe")).unwrap();
+        assert!(text.ends_with(
+            "known_networks = [\n    \"Home\",\n    # the office\n    \"Work\",\n    \"Cafe\",\n]\n"
+        ));
+    }
+
+    #[test]
+    fn adds_the_list_if_there_is_none() {
+        let profile = "\n[[profile]]\nname = \"work\"\nwireguard_interface = \"wg1\"\n\
+                       firewall_mark = 3\nrouting_table = 4\n";
+        let text = edited("add-missing", profile, |path| {
+            add_known_network(path, "Cafe")?;
+            let config = load(path)?;
+            assert!(config.knows("Cafe"));
+            assert_eq!(config.profiles.len(), 1);
+            Ok(())
+        })
+        .unwrap();
+        assert!(text.contains("known_networks = [\"Cafe\"]"));
+    }
+
+    #[test]
+    fn adds_to_a_list_of_tables() {
+        let tables = "\n[[known_networks]]\nssid = \"Home\"\n";
+        let text = edited("add-tables", tables, |path| {
+            add_known_network(path, "Cafe")?;
+            assert!(load(path)?.knows("Cafe"));
+            Ok(())
+        })
+        .unwrap();
+        assert!(text.ends_with("[[known_networks]]\nssid = \"Cafe\"\n"));
+    }
+
+    #[test]
+    fn adds_ssids_that_look_like_patterns_as_is() {
+        edited("add-pattern", "known_networks = []\n", |path| {
+            add_known_network(path, "regex:.*")?;
+            add_known_network(path, "glob:*")?;
+
+            let config = load(path)?;
+            assert!(config.knows("regex:.*"));
+            assert!(config.knows("glob:*"));
+            assert!(!config.knows("Home"));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn removes_exact_entries() {
+        let list = "known_networks = [\n    \"Home\",\n    { ssid = \"Cafe\", ignore_case = true },\n    \"glob:Cafe*\",\n]\n";
+        let text = edited("remove-lines", list, |path| {
+            assert_eq!(remove_known_network(path, "Cafe")?, 1);
+            assert!(remove_known_network(path, "Cafe").is_err());
+            assert!(remove_known_network(path, "Cafe-2").is_err());
+            Ok(())
+        })
+        .unwrap();
+        assert!(text.ends_with("known_networks = [\n    \"Home\",\n    \"glob:Cafe*\",\n]\n"));
+
+        let text = edited(
+            "remove-inline",
+            "known_networks = [\"Home\", \"Cafe\"]\n",
+            |path| {
+                assert_eq!(remove_known_network(path, "Home")?, 1);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert!(text.ends_with("known_networks = [\"Cafe\"]\n"));
+
+        let tables =
+            "\n[[known_networks]]\nssid = \"Home\"\n\n[[known_networks]]\nssid = \"Cafe\"\n";
+        let text = edited("remove-tables", tables, |path| {
+            assert_eq!(remove_known_network(path, "Home")?, 1);
+            Ok(())
+        })
+        .unwrap();
+        assert!(!text.contains("Home"));
+        assert!(text.contains("ssid = \"Cafe\""));
+    }
+
+    #[test]
+    fn removes_ssids_that_look_like_patterns() {
+        let list = "known_networks = [\"regex:.*\"]\n";
+        edited("remove-pattern", list, |path| {
+            // The pattern someone wrote by hand is not the network that is named like it
+            assert!(remove_known_network(path, "regex:.*").is_err());
+
+            add_known_network(path, "regex:.*")?;
+            assert_eq!(remove_known_network(path, "regex:.*")?, 1);
+            assert!(load(path)?.knows("Home"));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn nothing_is_written_when_the_list_is_broken() {
+        let text = edited("broken", "known_networks = 3\n", |path| {
+            assert!(add_known_network(path, "Cafe").is_err());
+            assert!(remove_known_network(path, "Cafe").is_err());
+            Ok(())
+        })
+        .unwrap();
+        assert!(text.ends_with("known_networks = 3\n"));
+    }
+
+    fn mac(last: u8) -> Option<MacAddr> {
+        Some(MacAddr([0xaa, 0xbb, 0xcc, 0xdd, 0xee, last]))
+    }
+
+    fn pinned() -> Config {
+        let networks = r#"known_networks = [
+    "Home",
+    { ssid = "Office", bssids = ["aa:bb:cc:dd:ee:01", "aa:bb:cc:dd:ee:02"] },
+    { ssid = "Office", bssids = ["aa:bb:cc:dd:ee:03"], security = ["wpa3-personal"] },
+    { ssid = "Lab", security = ["wpa2-enterprise"], eap_identity = "alice" },
+    { ssid = "Cafe", gateway_macs = ["aa:bb:cc:dd:ee:ff"] },
+]
+"#;
+        parse(&format!("{}{}", CONFIG, networks)).unwrap()
+    }
+
+    #[test]
+    fn trusts_known_networks() {
+        let config = pinned();
+        let wpa2 = Security::Wpa2Personal;
+
+        assert_eq!(
+            config.trust("Elsewhere", mac(1), wpa2, None),
+            Trust::Untrusted
+        );
+        assert_eq!(config.trust("Home", mac(9), wpa2, None), Trust::Trusted);
+        assert_eq!(config.trust("Home", None, wpa2, None), Trust::Trusted);
+        assert_eq!(
+            config.trust("Cafe", None, wpa2, None),
+            Trust::VerifyGateway(GatewayFingerprint {
+                gateway_macs: vec![mac(0xff).unwrap()],
+                learn: false,
+            })
+        );
+    }
+
+    #[test]
+    fn open_networks_are_never_trusted() {
+        let config = pinned();
+
+        assert_eq!(
+            config.trust("Home", None, Security::Open, None),
+            Trust::Insecure
+        );
+        // Even from an access point that isn't pinned
+        assert_eq!(
+            config.trust("Office", mac(9), Security::Open, None),
+            Trust::Insecure
+        );
+
+        let open = "known_networks = [{ ssid = \"Home\", security = [\"open\"] }]\n";
+        assert!(parse(&format!("{}{}", CONFIG, open)).is_err());
+    }
+
+    #[test]
+    fn pins_access_points() {
+        let config = pinned();
+        let wpa2 = Security::Wpa2Personal;
+
+        assert_eq!(config.trust("Office", mac(1), wpa2, None), Trust::Trusted);
+        assert_eq!(config.trust("Office", mac(2), wpa2, None), Trust::Trusted);
+        assert_eq!(
+            config.trust("Office", mac(9), wpa2, None),
+            Trust::UnexpectedBssid
+        );
+        assert_eq!(
+            config.trust("Office", None, wpa2, None),
+            Trust::UnexpectedBssid
+        );
+    }
+
+    #[test]
+    fn checks_the_security_of_the_pinned_entry() {
+        let config = pinned();
+
+        // Only the entry that pins the access point decides the security
+        let trust = config.trust("Office", mac(3), Security::Wpa2Personal, None);
+        assert_eq!(trust, Trust::UnexpectedSecurity);
+        let trust = config.trust("Office", mac(3), Security::Wpa3Personal, None);
+        assert_eq!(trust, Trust::Trusted);
+
+        let enterprise = Security::Wpa2Enterprise;
+        assert_eq!(
+            config.trust("Lab", None, enterprise, Some("alice")),
+            Trust::Trusted
+        );
+        let trust = config.trust("Lab", None, enterprise, Some("mallory"));
+        assert_eq!(trust, Trust::UnexpectedSecurity);
+        assert_eq!(
+            config.trust("Lab", None, enterprise, None),
+            Trust::UnexpectedSecurity
+        );
+        let trust = config.trust("Lab", None, Security::Wpa2Personal, Some("alice"));
+        assert_eq!(trust, Trust::UnexpectedSecurity);
+    }
+}
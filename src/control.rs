@@ -0,0 +1,448 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+
+use log::*;
+
+use std::fs;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::config::{self, Dns, Trust};
+use super::links::{SharedLinks, Verdict};
+use super::state::{Event, State, StateRx};
+use super::{wireguard, ConfigRx, Reloader};
+
+pub const SOCKET_PATH: &str = "/run/autovpn/control.sock";
+
+/// Whether the socket was bound here rather than passed by systemd, which removes its own.
+static BOUND: AtomicBool = AtomicBool::new(false);
+
+/// A request on the control socket, one JSON object per line such as
+/// `{"command": "pause", "minutes": 30}`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    Status,
+    /// Turns the VPN on whatever the network, with the default profile unless one is named.
+    Enable {
+        profile: Option<String>,
+    },
+    /// Turns the VPN off whatever the network.
+    Disable,
+    Pause {
+        minutes: u64,
+    },
+    /// Goes back to following the network.
+    Resume,
+    /// Adds the network of the wireless link to `known_networks` and reloads.
+    TrustCurrent,
+    /// Removes an SSID from `known_networks` and reloads.
+    Untrust {
+        ssid: String,
+    },
+    /// Reloads the config, like SIGHUP.
+    Reload,
+}
+
+/// The answer to a [`Request`], also one JSON object per line.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Response {
+    /// Says what was done.
+    Ok(String),
+    Status(Status),
+    Error(String),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Status {
+    pub state: String,
+    /// What is set up for the tunnel, if anything.
+    pub tunnel: Option<Tunnel>,
+    pub links: Vec<Link>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Tunnel {
+    pub profile: String,
+    pub wireguard_interface: String,
+    /// The table our routing rule points at.
+    pub routing_table: u32,
+    pub dns: Dns,
+    pub kill_switch: bool,
+    /// Only filled in on the control socket, since it has to be asked from the kernel.
+    pub interface: Option<Interface>,
+}
+
+/// What the kernel reports about the WireGuard interface.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Interface {
+    pub public_key: Option<String>,
+    pub listen_port: u16,
+    /// 0 if unset.
+    pub fwmark: u32,
+    pub peers: Vec<Peer>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Peer {
+    pub public_key: String,
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<String>,
+    /// Seconds since the last handshake, if there was one.
+    pub last_handshake: Option<u64>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub persistent_keepalive: Option<u16>,
+}
+
+impl From<&wireguard::Device> for Interface {
+    fn from(device: &wireguard::Device) -> Self {
+        let peers = device
+            .peers
+            .iter()
+            .map(|p| Peer {
+                public_key: p.public_key.to_string(),
+                endpoint: p.endpoint,
+                allowed_ips: p.allowed_ips.iter().map(|ip| ip.to_string()).collect(),
+                last_handshake: p
+                    .last_handshake
+                    .map(|t| t.elapsed().unwrap_or_default().as_secs()),
+                rx_bytes: p.rx_bytes,
+                tx_bytes: p.tx_bytes,
+                persistent_keepalive: p.persistent_keepalive,
+            })
+            .collect();
+
+        Interface {
+            public_key: device.public_key.map(|k| k.to_string()),
+            listen_port: device.listen_port,
+            fwmark: device.fwmark,
+            peers,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Link {
+    pub ifindex: u32,
+    pub ifname: Option<String>,
+    pub wireless: bool,
+    /// The SSID, or the name of a known wired network.
+    pub network: Option<String>,
+    /// How the network was judged when connecting, e.g. `unexpected-bssid`.
+    pub decision: Option<String>,
+    /// `trusted`, `untrusted` or `portal`, which may change once a check finishes.
+    pub verdict: String,
+}
+
+fn status(links: &SharedLinks, state: &StateRx, config: &ConfigRx) -> Status {
+    let state = state.borrow().clone();
+    let tunnel = state.profile().map(|p| Tunnel {
+        profile: p.name.clone(),
+        wireguard_interface: p.wireguard_interface.clone(),
+        routing_table: p.routing_table,
+        dns: p.dns.clone(),
+        kill_switch: config.borrow().kill_switch,
+        interface: None,
+    });
+
+    let links = links
+        .lock()
+        .unwrap()
+        .status()
+        .into_iter()
+        .map(|(ifindex, description, verdict)| Link {
+            ifindex,
+            ifname: description.as_ref().map(|d| d.ifname.clone()),
+            wireless: description.as_ref().is_some_and(|d| d.wireless),
+            network: description.as_ref().and_then(|d| d.network.clone()),
+            decision: description.map(|d| d.decision),
+            verdict: String::from(match verdict {
+                Verdict::Trusted => "trusted",
+                Verdict::Untrusted(_) => "untrusted",
+                Verdict::Portal => "portal",
+            }),
+        })
+        .collect();
+
+    Status {
+        state: state.to_string(),
+        tunnel,
+        links,
+    }
+}
+
+/// Carries out requests, whether they come from the control socket or from the bus.
+pub struct Control {
+    events: UnboundedSender<Event>,
+    links: SharedLinks,
+    state: StateRx,
+    config: ConfigRx,
+    reloader: Arc<Reloader>,
+}
+
+impl Control {
+    pub fn new(
+        events: UnboundedSender<Event>,
+        links: SharedLinks,
+        state: StateRx,
+        config: ConfigRx,
+        reloader: Arc<Reloader>,
+    ) -> Arc<Self> {
+        Arc::new(Control {
+            events,
+            links,
+            state,
+            config,
+            reloader,
+        })
+    }
+
+    pub fn state(&self) -> State {
+        self.state.borrow().clone()
+    }
+
+    /// A receiver that sees every change of the state.
+    pub fn subscribe(&self) -> StateRx {
+        self.state.clone()
+    }
+
+    pub fn status(&self) -> Status {
+        status(&self.links, &self.state, &self.config)
+    }
+
+    /// Like [`Control::status`], along with what the kernel reports about the tunnel.
+    async fn detailed_status(&self) -> Status {
+        let mut status = self.status();
+
+        if let Some(tunnel) = &mut status.tunnel {
+            match wireguard::get_device(&tunnel.wireguard_interface).await {
+                Ok(device) => tunnel.interface = Some(Interface::from(&device)),
+                Err(e) => debug!("failed to get {}: {:#}", tunnel.wireguard_interface, e),
+            }
+        }
+
+        status
+    }
+
+    fn send(&self, event: Event, message: String) -> Result<Response> {
+        info!("{} by request", message);
+        self.events
+            .send(event)
+            .map_err(|_| anyhow::anyhow!("shutting down"))?;
+        Ok(Response::Ok(message))
+    }
+
+    /// Adds the network of the only wireless link to `known_networks`.
+    fn trust_current(&self) -> Result<Response> {
+        let mut networks: Vec<_> = self
+            .links
+            .lock()
+            .unwrap()
+            .status()
+            .into_iter()
+            .filter_map(|(_, description, _)| description)
+            .filter(|d| d.wireless)
+            .filter_map(|d| Some((d.network?, d.decision)))
+            .collect();
+        networks.dedup_by(|a, b| a.0 == b.0);
+
+        let (ssid, decision) = match networks.as_slice() {
+            [] => anyhow::bail!("not connected to a wireless network"),
+            [network] => network,
+            _ => anyhow::bail!("connected to several wireless networks"),
+        };
+        if *decision != Trust::Untrusted.to_string() {
+            anyhow::bail!("'{}' is already a known network ({})", ssid, decision);
+        }
+
+        self.trust(ssid)
+    }
+
+    /// Adds `ssid` to `known_networks`, unless it is already known.
+    pub fn trust(&self, ssid: &str) -> Result<Response> {
+        if self.config.borrow().knows(ssid) {
+            anyhow::bail!("'{}' is already a known network", ssid);
+        }
+
+        config::add_known_network(self.reloader.path(), ssid)?;
+        self.reloader.reload()?;
+
+        let message = format!("now trusting '{}'", ssid);
+        info!("{} by request", message);
+        Ok(Response::Ok(message))
+    }
+
+    fn untrust(&self, ssid: &str) -> Result<Response> {
+        config::remove_known_network(self.reloader.path(), ssid)?;
+        self.reloader.reload()?;
+
+        let message = if self.config.borrow().knows(ssid) {
+            format!(
+                "removed '{}', but another known network still matches it",
+                ssid
+            )
+        } else {
+            format!("no longer trusting '{}'", ssid)
+        };
+        info!("{} by request", message);
+        Ok(Response::Ok(message))
+    }
+
+    pub fn handle(&self, request: Request) -> Result<Response> {
+        match request {
+            Request::Status => Ok(Response::Status(self.status())),
+            Request::Enable { profile } => {
+                let profile = {
+                    let config = self.config.borrow();
+                    match profile {
+                        Some(name) => config
+                            .profile_named(&name)
+                            .with_context(|| format!("no profile named '{}'", name))?
+                            .clone(),
+                        None => config.default_profile.clone(),
+                    }
+                };
+                let message = format!("turning the VPN on with profile '{}'", profile.name);
+                self.send(Event::Force(profile), message)
+            }
+            Request::Disable => self.send(Event::Pause(None), String::from("turning the VPN off")),
+            Request::Pause { minutes: 0 } => anyhow::bail!("can't pause for 0 minutes"),
+            Request::Pause { minutes } => {
+                let until = minutes
+                    .checked_mul(60)
+                    .and_then(|secs| Instant::now().checked_add(Duration::from_secs(secs)))
+                    .with_context(|| format!("can't pause for {} minutes", minutes))?;
+                self.send(
+                    Event::Pause(Some(until)),
+                    format!("pausing the VPN for {} minutes", minutes),
+                )
+            }
+            Request::Resume => {
+                self.send(Event::Resume, String::from("following the network again"))
+            }
+            Request::TrustCurrent => self.trust_current(),
+            Request::Untrust { ssid } => self.untrust(&ssid),
+            Request::Reload => {
+                self.reloader.reload()?;
+                Ok(Response::Ok(String::from("reloaded config")))
+            }
+        }
+    }
+
+    async fn serve(&self, stream: UnixStream) -> Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            let response = match serde_json::from_str(&line) {
+                Ok(Request::Status) => Response::Status(self.detailed_status().await),
+                Ok(request) => self
+                    .handle(request)
+                    .unwrap_or_else(|e| Response::Error(format!("{:#}", e))),
+                Err(e) => Response::Error(format!("invalid request: {}", e)),
+            };
+
+            let mut reply = serde_json::to_vec(&response)?;
+            reply.push(b'\n');
+            write.write_all(&reply).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Removes a socket left behind if the daemon didn't get to clean up. Anything else at `path` is
+/// left alone, since it is most likely a typo in the config.
+pub fn remove_stale_socket(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            fs::remove_file(path).with_context(|| format!("failed to remove {}", path.display()))
+        }
+        Ok(_) => anyhow::bail!("{} exists and isn't a socket", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("failed to look at {}", path.display())),
+    }
+}
+
+/// Binds the socket so that only root, and the members of `group` if one is given, can connect.
+fn bind(path: &Path, group: Option<&str>) -> Result<UnixListener> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    remove_stale_socket(path)?;
+
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("failed to bind {}", path.display()))?;
+
+    let mode = match group {
+        Some(name) => {
+            let group = nix::unistd::Group::from_name(name)?
+                .with_context(|| format!("control group '{}' doesn't exist", name))?;
+            std::os::unix::fs::chown(path, None, Some(group.gid.as_raw()))?;
+            0o660
+        }
+        None => 0o600,
+    };
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+
+    Ok(listener)
+}
+
+/// Serves the control socket, which systemd may have already set up and `passed`, in which case
+/// `control_group` is left to its `SocketGroup=`.
+pub fn setup(
+    control: Arc<Control>,
+    passed: Option<std::os::unix::net::UnixListener>,
+) -> Result<JoinHandle<()>> {
+    let listener = match passed {
+        Some(listener) => {
+            debug!("got control socket from systemd");
+            listener.set_nonblocking(true)?;
+            UnixListener::from_std(listener)?
+        }
+        None => {
+            let group = control.config.borrow().control_group.clone();
+            let listener = bind(Path::new(SOCKET_PATH), group.as_deref())
+                .context("failed to set up control socket")?;
+            BOUND.store(true, Ordering::SeqCst);
+            listener
+        }
+    };
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("failed to accept control connection: {}", e);
+                    continue;
+                }
+            };
+
+            let control = control.clone();
+            tokio::spawn(async move {
+                if let Err(e) = control.serve(stream).await {
+                    debug!("control connection failed: {:#}", e);
+                }
+            });
+        }
+    }))
+}
+
+/// Removes the socket once the daemon stops listening.
+pub fn cleanup() {
+    if BOUND.load(Ordering::SeqCst) {
+        let _ = fs::remove_file(SOCKET_PATH);
+    }
+}
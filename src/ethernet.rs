@@ -0,0 +1,286 @@
+use anyhow::Result;
+
+use neli::{
+    consts::{
+        rtnl::{Arphrd, Iff, IffFlags, Ifla, RtAddrFamily, Rtm},
+        socket::NlFamily,
+    },
+    rtnl::Ifinfomsg,
+    socket::tokio::NlSocket,
+    socket::NlSocketHandle,
+    types::{Buffer, RtBuffer},
+};
+
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout_at, Duration, Instant};
+
+use log::*;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::config::MacAddr;
+use super::gateway;
+use super::links::{Description, SharedLinks, Verdict};
+use super::{Config, ConfigRx};
+
+/// What identifies the network a wired link is plugged into.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Network {
+    ifname: String,
+    gateway_mac: Option<MacAddr>,
+    domain: Option<String>,
+}
+
+fn dhcp_domain(ifindex: u32) -> Option<String> {
+    // systemd-networkd keeps the current DHCP lease of each link here
+    let lease = std::fs::read_to_string(format!("/run/systemd/netif/leases/{}", ifindex)).ok()?;
+    lease
+        .lines()
+        .find_map(|l| l.strip_prefix("DOMAINNAME="))
+        .map(str::to_string)
+}
+
+fn is_wireless(ifname: &str) -> bool {
+    Path::new("/sys/class/net")
+        .join(ifname)
+        .join("wireless")
+        .exists()
+}
+
+/// Finds the network behind every monitored wired link that is up and has a default gateway.
+fn scan(config: &Config) -> Result<HashMap<u32, Network>> {
+    let mut socket = NlSocketHandle::connect(NlFamily::Route, None, &[])?;
+
+    let request = Ifinfomsg::new(
+        RtAddrFamily::Unspecified,
+        Arphrd::None,
+        0,
+        IffFlags::empty(),
+        IffFlags::empty(),
+        RtBuffer::<Ifla, Buffer>::new(),
+    );
+    let links: Vec<Ifinfomsg> = gateway::dump(&mut socket, Rtm::Getlink, request)?;
+
+    let mut networks = HashMap::new();
+    for link in links {
+        if link.ifi_type != Arphrd::Ether || !link.ifi_flags.contains(&Iff::LowerUp) {
+            continue;
+        }
+
+        let Ok(ifname) = link
+            .rtattrs
+            .get_attr_handle()
+            .get_attr_payload_as_with_len::<String>(Ifla::Ifname)
+        else {
+            continue;
+        };
+        if !config.monitors_wired(&ifname) || is_wireless(&ifname) {
+            continue;
+        }
+
+        // Without a gateway the link isn't configured yet, an address or route event follows
+        let ifindex = link.ifi_index as u32;
+        let Some(gateway) = gateway::default_gateway(&mut socket, ifindex)? else {
+            continue;
+        };
+
+        // The gateway may not be resolved yet, in which case the network is untrusted until a
+        // neighbour event brings its address
+        let gateway_mac = gateway::neighbour_mac(&mut socket, ifindex, gateway)?;
+
+        networks.insert(
+            ifindex,
+            Network {
+                ifname,
+                gateway_mac,
+                domain: dhcp_domain(ifindex),
+            },
+        );
+    }
+
+    Ok(networks)
+}
+
+/// Updates the verdicts of wired links that changed since the last scan, or of every link if
+/// `force` is set.
+async fn rescan(
+    links: &SharedLinks,
+    config: &Arc<Config>,
+    known: &mut HashMap<u32, Network>,
+    force: bool,
+) {
+    let c = config.clone();
+    let found = match tokio::task::spawn_blocking(move || scan(&c)).await {
+        Ok(Ok(found)) => found,
+        Ok(Err(e)) => {
+            error!("failed to scan wired links: {}", e);
+            return;
+        }
+        Err(e) => {
+            error!("wired link scan panicked: {}", e);
+            return;
+        }
+    };
+
+    let mut links = links.lock().unwrap();
+
+    for (ifindex, network) in known.iter() {
+        if !found.contains_key(ifindex) {
+            info!("{} left wired network", network.ifname);
+            links.set(*ifindex, None);
+        }
+    }
+
+    for (ifindex, network) in found.iter() {
+        if !force && known.get(ifindex) == Some(network) {
+            continue;
+        }
+
+        let mac = network.gateway_mac;
+        let domain = network.domain.as_deref();
+        let known = config.wired_network(mac, domain);
+        links.describe(
+            *ifindex,
+            Description {
+                ifname: network.ifname.clone(),
+                wireless: false,
+                network: known.map(|n| n.name.clone()),
+                bssid: None,
+                decision: String::from(if known.is_some() { "known" } else { "unknown" }),
+            },
+        );
+        let verdict = match known {
+            Some(n) => {
+                info!(
+                    "{} connected to known wired network '{}'",
+                    network.ifname, n.name
+                );
+                Verdict::Trusted
+            }
+            None => {
+                info!(
+                    "{} connected to unknown wired network (gateway {}, domain {})",
+                    network.ifname,
+                    mac.map_or_else(|| String::from("unknown"), |m| m.to_string()),
+                    domain.unwrap_or("none")
+                );
+                Verdict::Untrusted(config.default_profile.clone())
+            }
+        };
+        links.set(*ifindex, Some(verdict));
+    }
+
+    *known = found;
+}
+
+/// Joins the groups that tell about links, addresses, routes and neighbours changing.
+fn subscribe() -> Result<NlSocket> {
+    let handle = NlSocketHandle::connect(NlFamily::Route, None, &[])?;
+    handle.add_mcast_membership(&[
+        libc::RTNLGRP_LINK,
+        libc::RTNLGRP_NEIGH,
+        libc::RTNLGRP_IPV4_IFADDR,
+        libc::RTNLGRP_IPV4_ROUTE,
+    ])?;
+    let socket = NlSocket::new(handle)?;
+
+    debug!("got rtnetlink multicast notifications");
+    Ok(socket)
+}
+
+/// Waits for the next notifications on `socket`, forever if there is none.
+async fn recv(socket: &mut Option<NlSocket>, buffer: &mut Vec<u8>) -> Result<()> {
+    match socket {
+        Some(socket) => {
+            socket.recv::<Rtm, Buffer>(buffer).await?;
+            Ok(())
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Events come in bursts, a link coming up adds addresses, routes and neighbours. The links are
+/// looked at again once there were none for this long, or after `MAX_SETTLE` on a busy network.
+const SETTLE: Duration = Duration::from_millis(500);
+const MAX_SETTLE: Duration = Duration::from_secs(5);
+
+/// How long to wait after failing to receive, doubled every time it fails again in a row.
+const ERROR_DELAY: Duration = Duration::from_secs(1);
+/// How often receiving may fail in a row before giving up, which stops feeding the watchdog.
+const MAX_ERRORS: u32 = 6;
+
+/// Watches the wired links, which only subscribes to the notifications while `wired_interfaces`
+/// is set, since neighbour events keep coming on a busy network.
+pub fn setup(links: SharedLinks, mut config: ConfigRx) -> Result<JoinHandle<()>> {
+    let mut socket = match config.borrow().wired_interfaces.is_empty() {
+        true => None,
+        false => Some(subscribe()?),
+    };
+
+    let handle = tokio::spawn(async move {
+        let mut known = HashMap::new();
+        let mut buffer = Vec::new();
+        let mut force = false;
+        let mut errors = 0;
+
+        loop {
+            let c = config.borrow().clone();
+            let watching = !c.wired_interfaces.is_empty() || !known.is_empty();
+            if watching {
+                rescan(&links, &c, &mut known, force).await;
+            }
+            force = false;
+
+            if !watching {
+                socket = None;
+            } else if socket.is_none() {
+                match subscribe() {
+                    Ok(s) => socket = Some(s),
+                    Err(e) => {
+                        error!("failed to watch wired links: {}", e);
+                        break;
+                    }
+                }
+            }
+
+            tokio::select! {
+                received = recv(&mut socket, &mut buffer) => {
+                    if let Err(e) = received {
+                        // Usually ENOBUFS after a burst, so changes may have been missed
+                        errors += 1;
+                        if errors >= MAX_ERRORS {
+                            error!("failed to receive link notifications {} times, giving up: {}", errors, e);
+                            break;
+                        }
+                        error!("failed to receive link notifications: {}", e);
+                        sleep(ERROR_DELAY * 2u32.pow(errors - 1)).await;
+                        force = true;
+                        continue;
+                    }
+                    errors = 0;
+
+                    let until = Instant::now() + MAX_SETTLE;
+                    while let Ok(Ok(())) = timeout_at(
+                        (Instant::now() + SETTLE).min(until),
+                        recv(&mut socket, &mut buffer),
+                    )
+                    .await
+                    {}
+                }
+
+                changed = config.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+
+                    // Report every link again, with the verdicts of the new config
+                    force = true;
+                }
+            }
+        }
+    });
+
+    Ok(handle)
+}
@@ -0,0 +1,37 @@
+use anyhow::{Context, Result};
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::config::MacAddr;
+
+/// Gateways recorded by known networks in `learn` mode, keyed by SSID. This is kept apart from
+/// the config so that the daemon never has to rewrite it.
+const PATH: &str = "/var/lib/autovpn/fingerprints.toml";
+
+fn load() -> Result<HashMap<String, Vec<MacAddr>>> {
+    match std::fs::read_to_string(PATH) {
+        Ok(s) => toml::from_str(&s).with_context(|| format!("invalid {}", PATH)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e).with_context(|| format!("unable to read {}", PATH)),
+    }
+}
+
+pub fn learned(ssid: &str) -> Result<Vec<MacAddr>> {
+    Ok(load()?.remove(ssid).unwrap_or_default())
+}
+
+pub fn learn(ssid: &str, mac: MacAddr) -> Result<()> {
+    let mut fingerprints = load()?;
+    fingerprints.entry(ssid.to_string()).or_default().push(mac);
+
+    let path = Path::new(PATH);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    // Write to the side and rename so a crash never leaves a truncated file behind
+    let tmp = path.with_extension("toml.tmp");
+    std::fs::write(&tmp, toml::to_string(&fingerprints)?)?;
+    std::fs::rename(&tmp, path).with_context(|| format!("unable to write {}", PATH))
+}
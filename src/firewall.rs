@@ -0,0 +1,391 @@
+use anyhow::{Context, Result};
+
+use neli::{consts::socket::NlFamily, socket::NlSocket};
+
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::task::JoinHandle;
+
+use log::*;
+
+use std::io;
+
+use super::state::{Command, Request};
+use super::{ConfigRx, Profile};
+
+const TABLE: &str = "autovpn";
+const CHAIN: &str = "output";
+
+// Attribute types from linux/netfilter/nf_tables.h, which libc doesn't have
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_POLICY: u16 = 5;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_META_DREG: u16 = 1;
+const NFTA_META_KEY: u16 = 2;
+const NFTA_CMP_SREG: u16 = 1;
+const NFTA_CMP_OP: u16 = 2;
+const NFTA_CMP_DATA: u16 = 3;
+const NFTA_PAYLOAD_DREG: u16 = 1;
+const NFTA_PAYLOAD_BASE: u16 = 2;
+const NFTA_PAYLOAD_OFFSET: u16 = 3;
+const NFTA_PAYLOAD_LEN: u16 = 4;
+const NFTA_IMMEDIATE_DREG: u16 = 1;
+const NFTA_IMMEDIATE_DATA: u16 = 2;
+const NFTA_DATA_VALUE: u16 = 1;
+const NFTA_DATA_VERDICT: u16 = 2;
+const NFTA_VERDICT_CODE: u16 = 1;
+
+/// Interface names are compared as the whole zero padded buffer.
+const IFNAMSIZ: usize = 16;
+
+/// Builds a batch of nf_tables messages, which the kernel applies all at once or not at all.
+struct Batch {
+    buf: Vec<u8>,
+    seq: u32,
+    /// Every message asks for an ack, which is where errors are reported.
+    acks: usize,
+}
+
+impl Batch {
+    fn new() -> Self {
+        let mut batch = Batch {
+            buf: Vec::new(),
+            seq: 0,
+            acks: 0,
+        };
+        batch.header(
+            libc::NFNL_MSG_BATCH_BEGIN as u16,
+            libc::NLM_F_REQUEST as u16,
+            libc::NFPROTO_UNSPEC as u8,
+            |_| {},
+        );
+        batch
+    }
+
+    fn header(&mut self, ty: u16, flags: u16, family: u8, attrs: impl FnOnce(&mut Self)) {
+        let start = self.buf.len();
+        // The length is filled in once the attributes are written
+        self.buf.extend_from_slice(&[0; 4]);
+        self.buf.extend_from_slice(&ty.to_ne_bytes());
+        self.buf.extend_from_slice(&flags.to_ne_bytes());
+        self.buf.extend_from_slice(&self.seq.to_ne_bytes());
+        self.buf.extend_from_slice(&0u32.to_ne_bytes());
+        self.seq += 1;
+
+        // nfgenmsg, the batch messages name the subsystem they are for
+        let res_id = match family as i32 {
+            libc::NFPROTO_UNSPEC => libc::NFNL_SUBSYS_NFTABLES as u16,
+            _ => 0,
+        };
+        self.buf.push(family);
+        self.buf.push(libc::NFNETLINK_V0 as u8);
+        self.buf.extend_from_slice(&res_id.to_be_bytes());
+
+        attrs(self);
+
+        let len = (self.buf.len() - start) as u32;
+        self.buf[start..start + 4].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    fn message(&mut self, msg: i32, flags: i32, attrs: impl FnOnce(&mut Self)) {
+        let ty = (libc::NFNL_SUBSYS_NFTABLES << 8 | msg) as u16;
+        let flags = (libc::NLM_F_REQUEST | libc::NLM_F_ACK | flags) as u16;
+        self.header(ty, flags, libc::NFPROTO_INET as u8, attrs);
+        self.acks += 1;
+    }
+
+    fn attr(&mut self, ty: u16, data: &[u8]) {
+        self.buf
+            .extend_from_slice(&((4 + data.len()) as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&ty.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(self.buf.len().next_multiple_of(4), 0);
+    }
+
+    fn attr_str(&mut self, ty: u16, s: &str) {
+        let mut data = s.as_bytes().to_vec();
+        data.push(0);
+        self.attr(ty, &data);
+    }
+
+    /// Numbers in nf_tables attributes are big endian, unlike in the rest of netlink.
+    fn attr_u32(&mut self, ty: u16, value: i32) {
+        self.attr(ty, &(value as u32).to_be_bytes());
+    }
+
+    fn nested(&mut self, ty: u16, attrs: impl FnOnce(&mut Self)) {
+        let start = self.buf.len();
+        self.buf.extend_from_slice(&[0; 2]);
+        self.buf
+            .extend_from_slice(&(ty | libc::NLA_F_NESTED as u16).to_ne_bytes());
+
+        attrs(self);
+
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    fn table(&mut self, msg: i32, flags: i32) {
+        self.message(msg, flags, |b| b.attr_str(NFTA_TABLE_NAME, TABLE));
+    }
+
+    fn expr(&mut self, name: &str, data: impl FnOnce(&mut Self)) {
+        self.nested(NFTA_LIST_ELEM, |b| {
+            b.attr_str(NFTA_EXPR_NAME, name);
+            b.nested(NFTA_EXPR_DATA, data);
+        });
+    }
+
+    fn meta(&mut self, key: i32) {
+        self.expr("meta", |b| {
+            b.attr_u32(NFTA_META_DREG, libc::NFT_REG_1);
+            b.attr_u32(NFTA_META_KEY, key);
+        });
+    }
+
+    fn payload(&mut self, base: i32, offset: i32, len: i32) {
+        self.expr("payload", |b| {
+            b.attr_u32(NFTA_PAYLOAD_DREG, libc::NFT_REG_1);
+            b.attr_u32(NFTA_PAYLOAD_BASE, base);
+            b.attr_u32(NFTA_PAYLOAD_OFFSET, offset);
+            b.attr_u32(NFTA_PAYLOAD_LEN, len);
+        });
+    }
+
+    fn cmp_eq(&mut self, data: &[u8]) {
+        self.expr("cmp", |b| {
+            b.attr_u32(NFTA_CMP_SREG, libc::NFT_REG_1);
+            b.attr_u32(NFTA_CMP_OP, libc::NFT_CMP_EQ);
+            b.nested(NFTA_CMP_DATA, |b| b.attr(NFTA_DATA_VALUE, data));
+        });
+    }
+
+    fn accept(&mut self) {
+        self.expr("immediate", |b| {
+            b.attr_u32(NFTA_IMMEDIATE_DREG, libc::NFT_REG_VERDICT);
+            b.nested(NFTA_IMMEDIATE_DATA, |b| {
+                b.nested(NFTA_DATA_VERDICT, |b| {
+                    b.attr_u32(NFTA_VERDICT_CODE, libc::NF_ACCEPT);
+                });
+            });
+        });
+    }
+
+    /// Appends a rule that accepts whatever `matches` matches.
+    fn accept_rule(&mut self, matches: impl FnOnce(&mut Self)) {
+        self.message(
+            libc::NFT_MSG_NEWRULE,
+            libc::NLM_F_CREATE | libc::NLM_F_APPEND,
+            |b| {
+                b.attr_str(NFTA_RULE_TABLE, TABLE);
+                b.attr_str(NFTA_RULE_CHAIN, CHAIN);
+                b.nested(NFTA_RULE_EXPRESSIONS, |b| {
+                    matches(b);
+                    b.accept();
+                });
+            },
+        );
+    }
+
+    fn accept_oifname(&mut self, ifname: &str) {
+        let mut name = [0; IFNAMSIZ];
+        let len = ifname.len().min(IFNAMSIZ - 1);
+        name[..len].copy_from_slice(&ifname.as_bytes()[..len]);
+
+        self.accept_rule(|b| {
+            b.meta(libc::NFT_META_OIFNAME);
+            b.cmp_eq(&name);
+        });
+    }
+
+    fn accept_udp_dport(&mut self, port: u16) {
+        self.accept_rule(|b| {
+            b.meta(libc::NFT_META_L4PROTO);
+            b.cmp_eq(&[libc::IPPROTO_UDP as u8]);
+            // The destination port follows the source port
+            b.payload(libc::NFT_PAYLOAD_TRANSPORT_HEADER, 2, 2);
+            b.cmp_eq(&port.to_be_bytes());
+        });
+    }
+
+    fn send(mut self) -> Result<()> {
+        self.header(
+            libc::NFNL_MSG_BATCH_END as u16,
+            libc::NLM_F_REQUEST as u16,
+            libc::NFPROTO_UNSPEC as u8,
+            |_| {},
+        );
+
+        let socket = NlSocket::connect(NlFamily::Netfilter, None, &[])?;
+        socket.send(&self.buf, 0)?;
+
+        let mut acks = 0;
+        while acks < self.acks {
+            for code in recv_errors(&socket)? {
+                if code != 0 {
+                    return Err(io::Error::from_raw_os_error(-code).into());
+                }
+                acks += 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Receives a datagram and returns the error codes of the acks in it, 0 meaning success.
+fn recv_errors(socket: &NlSocket) -> Result<Vec<i32>> {
+    let mut buf = vec![0; 8192];
+    let len = socket.recv(&mut buf, 0)?;
+    let mut buf = &buf[..len];
+
+    let mut codes = Vec::new();
+    while buf.len() >= 16 {
+        let len = u32::from_ne_bytes(buf[..4].try_into()?) as usize;
+        let ty = u16::from_ne_bytes(buf[4..6].try_into()?);
+        if len < 16 || len > buf.len() {
+            anyhow::bail!("malformed netlink message");
+        }
+
+        if ty as i32 == libc::NLMSG_ERROR && len >= 20 {
+            codes.push(i32::from_ne_bytes(buf[16..20].try_into()?));
+        }
+        buf = &buf[len.next_multiple_of(4).min(buf.len())..];
+    }
+
+    Ok(codes)
+}
+
+/// Replaces the table with one that drops everything that doesn't go through the tunnel of
+/// `profile`, besides what is needed to reach the WireGuard endpoint and get an address.
+fn install(profile: &Profile) -> Result<()> {
+    let mut batch = Batch::new();
+
+    // Creating the table first means deleting it can't fail, so this replaces whatever was there
+    batch.table(libc::NFT_MSG_NEWTABLE, libc::NLM_F_CREATE);
+    batch.table(libc::NFT_MSG_DELTABLE, 0);
+    batch.table(libc::NFT_MSG_NEWTABLE, libc::NLM_F_CREATE);
+
+    batch.message(libc::NFT_MSG_NEWCHAIN, libc::NLM_F_CREATE, |b| {
+        b.attr_str(NFTA_CHAIN_TABLE, TABLE);
+        b.attr_str(NFTA_CHAIN_NAME, CHAIN);
+        b.nested(NFTA_CHAIN_HOOK, |b| {
+            b.attr_u32(NFTA_HOOK_HOOKNUM, libc::NF_INET_LOCAL_OUT);
+            b.attr_u32(NFTA_HOOK_PRIORITY, libc::NF_IP_PRI_FILTER);
+        });
+        b.attr_u32(NFTA_CHAIN_POLICY, libc::NF_DROP);
+        b.attr_str(NFTA_CHAIN_TYPE, "filter");
+    });
+
+    batch.accept_oifname("lo");
+    batch.accept_oifname(&profile.wireguard_interface);
+    // WireGuard marks its own packets to the endpoint with the firewall mark
+    batch.accept_rule(|b| {
+        b.meta(libc::NFT_META_MARK);
+        b.cmp_eq(&profile.firewall_mark.to_ne_bytes());
+    });
+    // DHCP and DHCPv6, along with neighbour discovery and router solicitations
+    batch.accept_udp_dport(67);
+    batch.accept_udp_dport(547);
+    batch.accept_rule(|b| {
+        b.meta(libc::NFT_META_L4PROTO);
+        b.cmp_eq(&[libc::IPPROTO_ICMPV6 as u8]);
+    });
+
+    batch.send()
+}
+
+fn remove() -> Result<()> {
+    let mut batch = Batch::new();
+    batch.table(libc::NFT_MSG_NEWTABLE, libc::NLM_F_CREATE);
+    batch.table(libc::NFT_MSG_DELTABLE, 0);
+    batch.send()
+}
+
+fn exists() -> Result<bool> {
+    let mut batch = Batch {
+        buf: Vec::new(),
+        seq: 0,
+        acks: 0,
+    };
+    // Requests for information aren't batched
+    batch.table(libc::NFT_MSG_GETTABLE, 0);
+
+    let socket = NlSocket::connect(NlFamily::Netfilter, None, &[])?;
+    socket.send(&batch.buf, 0)?;
+
+    loop {
+        match recv_errors(&socket)?.first() {
+            None => continue,
+            Some(0) => return Ok(true),
+            Some(code) if -code == libc::ENOENT => return Ok(false),
+            Some(code) => return Err(io::Error::from_raw_os_error(-code).into()),
+        }
+    }
+}
+
+async fn blocking(f: impl FnOnce() -> Result<()> + Send + 'static) -> Result<()> {
+    tokio::task::spawn_blocking(f).await?
+}
+
+async fn run(command: Command, config: &ConfigRx) -> Result<()> {
+    let kill_switch = config.borrow().kill_switch;
+
+    match command {
+        Command::Enable { new, .. } if kill_switch => blocking(move || install(&new))
+            .await
+            .context("failed to install kill switch"),
+        Command::Enable { .. } | Command::Disable(_) => {
+            let result = blocking(remove)
+                .await
+                .context("failed to remove kill switch");
+            // Without the kill switch this only cleans up after a config that had it, and
+            // nf_tables may not even be available
+            match result {
+                Err(e) if !kill_switch => {
+                    debug!("{:#}", e);
+                    Ok(())
+                }
+                result => result,
+            }
+        }
+        Command::Reconcile(profile) => {
+            if !kill_switch {
+                return Ok(());
+            }
+
+            let exists = tokio::task::spawn_blocking(exists)
+                .await?
+                .context("failed to check for kill switch")?;
+            match (profile, exists) {
+                (Some(profile), false) => {
+                    warn!("kill switch table is missing, installing it again");
+                    blocking(move || install(&profile)).await
+                }
+                (None, true) => {
+                    warn!("found kill switch table which isn't needed, removing it");
+                    blocking(remove).await
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+pub fn setup(mut rx: UnboundedReceiver<Request>, config: ConfigRx) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Some((command, ack)) = rx.recv().await {
+            let _ = ack.send(run(command, &config).await);
+        }
+    })
+}
@@ -0,0 +1,159 @@
+use anyhow::Result;
+
+use neli::{
+    consts::{
+        nl::{NlmF, NlmFFlags},
+        rtnl::Rtm,
+        socket::NlFamily,
+    },
+    nl::{NlPayload, Nlmsghdr},
+    rtnl::{Ndmsg, Rtmsg},
+    socket::NlSocketHandle,
+    types::{Buffer, RtBuffer},
+    FromBytesWithInput, Size, ToBytes,
+};
+
+use tokio::time::{sleep, Duration, Instant};
+
+use std::fmt::Debug;
+use std::net::Ipv4Addr;
+
+use super::config::MacAddr;
+
+/// Sends a dump request and collects every payload of the reply.
+pub fn dump<Q, P>(socket: &mut NlSocketHandle, rtm: Rtm, request: Q) -> Result<Vec<P>>
+where
+    Q: Size + ToBytes + Debug,
+    P: for<'a> FromBytesWithInput<'a, Input = usize> + Debug,
+{
+    socket.send(Nlmsghdr::new(
+        None,
+        rtm,
+        NlmFFlags::new(&[NlmF::Request, NlmF::Dump]),
+        None,
+        None,
+        NlPayload::Payload(request),
+    ))?;
+
+    let mut payloads = Vec::new();
+    for msg in socket.iter::<Rtm, P>(false) {
+        let msg = msg.map_err(|e| anyhow::anyhow!("failed to dump {:?}: {}", rtm, e))?;
+        if let NlPayload::Payload(p) = msg.nl_payload {
+            payloads.push(p);
+        }
+    }
+
+    Ok(payloads)
+}
+
+fn parse_ipv4(bytes: &[u8]) -> Option<Ipv4Addr> {
+    <[u8; 4]>::try_from(bytes).ok().map(Ipv4Addr::from)
+}
+
+fn parse_u32(bytes: &[u8]) -> Option<u32> {
+    bytes.try_into().ok().map(u32::from_ne_bytes)
+}
+
+/// Finds the IPv4 default gateway that is reached through `ifindex`.
+pub fn default_gateway(socket: &mut NlSocketHandle, ifindex: u32) -> Result<Option<Ipv4Addr>> {
+    use neli::consts::rtnl::*;
+
+    let request = Rtmsg {
+        rtm_family: RtAddrFamily::Inet,
+        rtm_dst_len: 0,
+        rtm_src_len: 0,
+        rtm_tos: 0,
+        rtm_table: RtTable::Unspec,
+        rtm_protocol: Rtprot::Unspec,
+        rtm_scope: RtScope::Universe,
+        rtm_type: Rtn::Unspec,
+        rtm_flags: RtmFFlags::new(&[]),
+        rtattrs: RtBuffer::new(),
+    };
+
+    let routes: Vec<Rtmsg> = dump(socket, Rtm::Getroute, request)?;
+    let gateway = routes
+        .iter()
+        .filter(|r| r.rtm_dst_len == 0 && r.rtm_table == RtTable::Main)
+        .find_map(|r| {
+            let attrs = r.rtattrs.get_attr_handle();
+            let oif = attrs
+                .get_attribute(Rta::Oif)
+                .and_then(|a| parse_u32(a.rta_payload.as_ref()))?;
+            if oif != ifindex {
+                return None;
+            }
+
+            attrs
+                .get_attribute(Rta::Gateway)
+                .and_then(|a| parse_ipv4(a.rta_payload.as_ref()))
+        });
+
+    Ok(gateway)
+}
+
+/// Looks up the hardware address of `addr` in the neighbour table of `ifindex`.
+pub fn neighbour_mac(
+    socket: &mut NlSocketHandle,
+    ifindex: u32,
+    addr: Ipv4Addr,
+) -> Result<Option<MacAddr>> {
+    use neli::consts::rtnl::*;
+
+    let request = Ndmsg::new(
+        RtAddrFamily::Inet,
+        0,
+        NudFlags::new(&[]),
+        NtfFlags::new(&[]),
+        Rtn::Unspec,
+        RtBuffer::<Nda, Buffer>::new(),
+    );
+
+    let neighbours: Vec<Ndmsg> = dump(socket, Rtm::Getneigh, request)?;
+    let mac = neighbours
+        .iter()
+        .filter(|n| n.ndm_index as u32 == ifindex)
+        // Entries that failed to resolve still carry the last known address
+        .filter(|n| !n.ndm_state.contains(&Nud::Failed) && !n.ndm_state.contains(&Nud::Incomplete))
+        .find_map(|n| {
+            let attrs = n.rtattrs.get_attr_handle();
+            let dst = attrs
+                .get_attribute(Nda::Dst)
+                .and_then(|a| parse_ipv4(a.rta_payload.as_ref()))?;
+            if dst != addr {
+                return None;
+            }
+
+            attrs
+                .get_attribute(Nda::Lladdr)
+                .and_then(|a| MacAddr::from_bytes(a.rta_payload.as_ref()))
+        });
+
+    Ok(mac)
+}
+
+fn gateway_mac(ifindex: u32) -> Result<Option<MacAddr>> {
+    let mut socket = NlSocketHandle::connect(NlFamily::Route, None, &[])?;
+
+    match default_gateway(&mut socket, ifindex)? {
+        Some(gateway) => neighbour_mac(&mut socket, ifindex, gateway),
+        None => Ok(None),
+    }
+}
+
+/// Waits for the default gateway of `ifindex` to appear and be resolved, returning its hardware
+/// address, or `None` if that doesn't happen within `limit`.
+pub async fn wait_for_gateway_mac(ifindex: u32, limit: Duration) -> Result<Option<MacAddr>> {
+    let start = Instant::now();
+
+    loop {
+        if let Some(mac) = tokio::task::spawn_blocking(move || gateway_mac(ifindex)).await?? {
+            return Ok(Some(mac));
+        }
+
+        if start.elapsed() >= limit {
+            return Ok(None);
+        }
+        sleep(Duration::from_millis(500)).await;
+    }
+}
@@ -0,0 +1,160 @@
+use anyhow::{Context, Result};
+
+use tokio::process::Command;
+use tokio::time::{timeout, Duration};
+
+use log::*;
+
+use std::process::Stdio;
+
+use super::config;
+use super::links::{SharedLinks, Verdict};
+use super::{ConfigRx, Profile};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Hook {
+    PreEnable,
+    PostEnable,
+    PreDisable,
+    PostDisable,
+    Unhealthy,
+    Healthy,
+}
+
+impl Hook {
+    fn name(self) -> &'static str {
+        match self {
+            Hook::PreEnable => "pre-enable",
+            Hook::PostEnable => "post-enable",
+            Hook::PreDisable => "pre-disable",
+            Hook::PostDisable => "post-disable",
+            Hook::Unhealthy => "unhealthy",
+            Hook::Healthy => "healthy",
+        }
+    }
+
+    fn command(self, hooks: &config::Hooks) -> Option<&String> {
+        match self {
+            Hook::PreEnable => hooks.pre_enable.as_ref(),
+            Hook::PostEnable => hooks.post_enable.as_ref(),
+            Hook::PreDisable => hooks.pre_disable.as_ref(),
+            Hook::PostDisable => hooks.post_disable.as_ref(),
+            Hook::Unhealthy => hooks.unhealthy.as_ref(),
+            Hook::Healthy => hooks.healthy.as_ref(),
+        }
+    }
+
+    fn is_pre(self) -> bool {
+        matches!(self, Hook::PreEnable | Hook::PreDisable)
+    }
+}
+
+/// Site-specific commands run around turning the tunnel on and off, and when it stops or starts
+/// getting handshakes. They are run with `sh -c` and get the details in the environment:
+///
+/// - `AUTOVPN_HOOK`, e.g. `pre-enable`
+/// - `AUTOVPN_REASON`, what caused the transition, e.g. `network`, `pause` or `health`
+/// - `AUTOVPN_PROFILE` and `AUTOVPN_WIREGUARD_INTERFACE`
+/// - `AUTOVPN_INTERFACE`, `AUTOVPN_SSID` and `AUTOVPN_BSSID` of the link the decision was made
+///   for, left unset if there is none or it isn't known
+///
+/// Their output goes to the log.
+pub struct Hooks {
+    links: SharedLinks,
+    config: ConfigRx,
+}
+
+impl Hooks {
+    pub fn new(links: SharedLinks, config: ConfigRx) -> Self {
+        Hooks { links, config }
+    }
+
+    /// The environment describing the link that needs the tunnel, or any link if none does.
+    fn link_env(&self) -> Vec<(&'static str, String)> {
+        let links = self.links.lock().unwrap().status();
+        let description = links
+            .iter()
+            .find(|(_, _, verdict)| matches!(verdict, Verdict::Untrusted(_)))
+            .or_else(|| links.first())
+            .and_then(|(_, description, _)| description.clone());
+
+        let mut env = Vec::new();
+        if let Some(description) = description {
+            env.push(("AUTOVPN_INTERFACE", description.ifname));
+            if description.wireless {
+                if let Some(ssid) = description.network {
+                    env.push(("AUTOVPN_SSID", ssid));
+                }
+            }
+            if let Some(bssid) = description.bssid {
+                env.push(("AUTOVPN_BSSID", bssid.to_string()));
+            }
+        }
+        env
+    }
+
+    /// Runs `hook` if one is set. Failures are only logged, unless a pre hook failed and `veto`
+    /// is set, in which case the transition shouldn't go ahead.
+    pub async fn run(&self, hook: Hook, profile: Option<&Profile>, reason: &str) -> Result<()> {
+        let (command, limit, veto) = {
+            let config = self.config.borrow();
+            let Some(command) = hook.command(&config.hooks) else {
+                return Ok(());
+            };
+            (
+                command.clone(),
+                Duration::from_secs(config.hooks.timeout),
+                config.hooks.veto,
+            )
+        };
+
+        let mut child = Command::new("sh");
+        child
+            .arg("-c")
+            .arg(&command)
+            .env("AUTOVPN_HOOK", hook.name())
+            .env("AUTOVPN_REASON", reason)
+            .envs(self.link_env())
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        if let Some(profile) = profile {
+            child
+                .env("AUTOVPN_PROFILE", &profile.name)
+                .env("AUTOVPN_WIREGUARD_INTERFACE", &profile.wireguard_interface);
+        }
+
+        debug!("running {} hook", hook.name());
+        let result = match timeout(limit, child.output()).await {
+            Ok(output) => output.with_context(|| format!("failed to run {} hook", hook.name())),
+            Err(_) => Err(anyhow::anyhow!(
+                "{} hook took longer than {}s",
+                hook.name(),
+                limit.as_secs()
+            )),
+        };
+
+        let result = result.and_then(|output| {
+            for line in String::from_utf8_lossy(&output.stdout).lines() {
+                info!("{} hook: {}", hook.name(), line);
+            }
+            for line in String::from_utf8_lossy(&output.stderr).lines() {
+                warn!("{} hook: {}", hook.name(), line);
+            }
+
+            if output.status.success() {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("{} hook {}", hook.name(), output.status))
+            }
+        });
+
+        match result {
+            Err(e) if veto && hook.is_pre() => Err(e),
+            Err(e) => {
+                error!("{:#}", e);
+                Ok(())
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+}
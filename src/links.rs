@@ -0,0 +1,97 @@
+use tokio::sync::mpsc::UnboundedSender;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use super::config::MacAddr;
+use super::state::{Event, Target};
+use super::Profile;
+
+#[derive(Clone)]
+pub enum Verdict {
+    Trusted,
+    Untrusted(Arc<Profile>),
+    /// Untrusted, but behind a captive portal that has to be logged into before the tunnel can
+    /// come up.
+    Portal,
+}
+
+/// What a link is connected to and how it was judged, kept for the status and the hooks.
+#[derive(Clone, Debug)]
+pub struct Description {
+    pub ifname: String,
+    pub wireless: bool,
+    /// The SSID, or the name of a known wired network.
+    pub network: Option<String>,
+    pub bssid: Option<MacAddr>,
+    pub decision: String,
+}
+
+/// The trust verdicts of every connected link, wireless or wired. The VPN is wanted if any link
+/// is on an untrusted network, and not otherwise. While a link is behind a captive portal and no
+/// other link needs the VPN, nothing is asked for so the DNS and rules are left as they are.
+pub struct Links {
+    events: UnboundedSender<Event>,
+    /// Keyed by ifindex, ordered so that the profile picked with several untrusted links is stable.
+    verdicts: BTreeMap<u32, Verdict>,
+    descriptions: BTreeMap<u32, Description>,
+}
+
+pub type SharedLinks = Arc<Mutex<Links>>;
+
+impl Links {
+    pub fn new(events: UnboundedSender<Event>) -> SharedLinks {
+        Arc::new(Mutex::new(Links {
+            events,
+            verdicts: BTreeMap::new(),
+            descriptions: BTreeMap::new(),
+        }))
+    }
+
+    /// Records the verdict of a link, `None` meaning it is disconnected.
+    pub fn set(&mut self, ifindex: u32, verdict: Option<Verdict>) {
+        match verdict {
+            Some(v) => self.verdicts.insert(ifindex, v),
+            None => {
+                self.descriptions.remove(&ifindex);
+                self.verdicts.remove(&ifindex)
+            }
+        };
+
+        self.update();
+    }
+
+    /// Records what a link connected to, which should come along with its first verdict.
+    pub fn describe(&mut self, ifindex: u32, description: Description) {
+        self.descriptions.insert(ifindex, description);
+    }
+
+    /// Every connected link by ifindex, with its description if it has one.
+    pub fn status(&self) -> Vec<(u32, Option<Description>, Verdict)> {
+        self.verdicts
+            .iter()
+            .map(|(&ifindex, v)| (ifindex, self.descriptions.get(&ifindex).cloned(), v.clone()))
+            .collect()
+    }
+
+    fn update(&mut self) {
+        let profile = self.verdicts.values().find_map(|v| match v {
+            Verdict::Untrusted(profile) => Some(profile.clone()),
+            Verdict::Trusted | Verdict::Portal => None,
+        });
+
+        let portal = self.verdicts.values().any(|v| matches!(v, Verdict::Portal));
+        if profile.is_none() && portal {
+            return;
+        }
+
+        let target = match profile {
+            Some(profile) => Target::Untrusted(profile),
+            None if self.verdicts.is_empty() => Target::Disconnected,
+            None => Target::Trusted,
+        };
+
+        // The state machine is only gone when shutting down
+        let _ = self.events.send(Event::Network(target));
+    }
+}
@@ -1,70 +1,431 @@
+mod bus;
+mod client;
+mod config;
+mod control;
+mod ethernet;
+mod fingerprints;
+mod firewall;
+mod gateway;
+mod hooks;
+mod links;
+mod matcher;
+mod metrics;
 mod networkd;
+mod notify;
+mod portal;
 mod rule;
+mod security;
+mod state;
+mod supplicant;
+mod systemd;
 mod wifi;
 mod wireguard;
 
 use anyhow::{Context, Result};
-use std::sync::atomic::{AtomicBool, Ordering};
+use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::broadcast::channel;
-use tokio::time::{sleep, Duration};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, timeout_at, Duration, Instant};
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-pub enum Msg {
-    Enable,
+const CONFIG_PATH: &str = "/etc/autovpn/config.toml";
+
+/// The signals the daemon reacts to, shown at the end of `--help`.
+const SIGNALS: &str = "\
+Signals:
+  SIGHUP           Reload the config, like the control socket's reload
+  SIGUSR1          Turn the VPN off until resumed, like `autovpn pause` without an end
+  SIGUSR2          Follow the network again, like `autovpn resume`
+  SIGINT, SIGTERM  Tear everything down and exit";
+
+#[derive(Parser)]
+#[command(version, about, after_help = SIGNALS)]
+struct Args {
+    /// Path to the config file
+    #[arg(short, long, default_value = CONFIG_PATH)]
+    config: PathBuf,
+
+    /// Fork into the background, unless started by systemd
+    #[arg(short, long)]
+    daemon: bool,
+
+    /// Stay in the foreground, which is the default without --daemon
+    #[arg(short, long, conflicts_with = "daemon")]
+    foreground: bool,
+
+    /// Log filter, e.g. `debug` or `autovpn=trace`, overrides RUST_LOG
+    #[arg(short, long)]
+    log_level: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check that the config is valid and exit
+    CheckConfig,
+    /// Show what the running daemon is doing
+    Status,
+    /// Add the current wireless network to known_networks
+    TrustCurrent,
+    /// Remove a network from known_networks
+    Untrust { ssid: String },
+    /// Turn the VPN off for a while, e.g. `30m` or `2h`
+    Pause {
+        #[arg(value_parser = client::parse_minutes)]
+        duration: u64,
+    },
+    /// Follow the network again after pause, enable or disable
+    Resume,
+    /// Turn the VPN on whatever the network
+    Enable {
+        /// Profile to use instead of the default one
+        #[arg(short, long)]
+        profile: Option<String>,
+    },
+    /// Turn the VPN off whatever the network, until resumed
     Disable,
-    Quit,
+    /// Show a notification, run by the daemon as the logged in user
+    #[command(hide = true)]
+    Notify {
+        #[command(subcommand)]
+        notice: notify::Notice,
+    },
+}
+
+impl Command {
+    /// What to ask the running daemon for, if this is a client command.
+    fn request(&self) -> Option<control::Request> {
+        Some(match self {
+            Command::CheckConfig | Command::Notify { .. } => return None,
+            Command::Status => control::Request::Status,
+            Command::TrustCurrent => control::Request::TrustCurrent,
+            Command::Untrust { ssid } => control::Request::Untrust { ssid: ssid.clone() },
+            Command::Pause { duration } => control::Request::Pause { minutes: *duration },
+            Command::Resume => control::Request::Resume,
+            Command::Enable { profile } => control::Request::Enable {
+                profile: profile.clone(),
+            },
+            Command::Disable => control::Request::Disable,
+        })
+    }
 }
 
-#[derive(serde::Deserialize)]
-pub struct Config {
-    wireguard_interface: String,
-    wlan_interface: String,
-    known_networks: Vec<String>,
-    firewall_mark: u32,
-    routing_table: u32,
-    ipv6: bool,
+pub use config::{Config, Profile};
+
+/// The current config, replaced as a whole whenever it is reloaded.
+pub type ConfigRx = watch::Receiver<Arc<Config>>;
+
+/// Replaces the current config with the one at the path it was loaded from.
+pub struct Reloader {
+    path: PathBuf,
+    config_tx: watch::Sender<Arc<Config>>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    pretty_env_logger::init();
-    let config = Arc::new({
-        match std::fs::read("/etc/autovpn/config.toml") {
-            Ok(c) => toml::from_str::<Config>(&String::from_utf8_lossy(&c))
-                .context("invalid config.toml")?,
-            Err(e) => {
-                log::error!("unable to read config at /etc/autovpn/config.toml: {}", e);
-                return Err(e.into());
+impl Reloader {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A config that fails to load is rejected and the old one is kept.
+    pub fn reload(&self) -> Result<()> {
+        let config = config::load(&self.path)?;
+        log::info!("reloaded config");
+        self.config_tx.send_replace(Arc::new(config));
+        Ok(())
+    }
+}
+
+/// Pauses the VPN on SIGUSR1 and resumes it on SIGUSR2, see [`SIGNALS`].
+fn setup_pause(events: UnboundedSender<state::Event>) -> Result<JoinHandle<()>> {
+    let mut pause = signal(SignalKind::user_defined1()).context("failed to set SIGUSR1 handler")?;
+    let mut resume =
+        signal(SignalKind::user_defined2()).context("failed to set SIGUSR2 handler")?;
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                _ = pause.recv() => state::Event::Pause(None),
+                _ = resume.recv() => state::Event::Resume,
+            };
+            if events.send(event).is_err() {
+                break;
             }
         }
+    }))
+}
+
+fn check_config(config: &Config) -> Result<()> {
+    let missing = config.missing_interfaces();
+    if !missing.is_empty() {
+        anyhow::bail!("interfaces not found: {}", missing.join(", "));
+    }
+
+    println!("config is valid");
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let mut logger = pretty_env_logger::formatted_builder();
+    match &args.log_level {
+        Some(filter) => logger.parse_filters(filter),
+        None => logger.parse_default_env(),
+    };
+    logger.init();
+
+    // Client commands only talk to the daemon, so they don't need to read the config
+    if let Some(request) = args.command.as_ref().and_then(Command::request) {
+        return client::run(request);
+    }
+    if let Some(Command::Notify { notice }) = &args.command {
+        return notify::show(notice);
+    }
+
+    let config = match config::load(&args.config) {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!("{:#}", e);
+            return Err(e);
+        }
+    };
+
+    if let Some(Command::CheckConfig) = args.command {
+        return check_config(&config);
+    }
+
+    // The config is read again when reloading, which has to work from any directory
+    let config_path = args
+        .config
+        .canonicalize()
+        .with_context(|| format!("unable to resolve {}", args.config.display()))?;
+
+    let socket = systemd::take_environment();
+
+    // systemd already runs us in the background, and expects to hear from the pid it started
+    if args.daemon && !systemd::supervised() {
+        // Keep stderr open so that logs still end up wherever they were going
+        nix::unistd::daemon(false, true).context("failed to daemonize")?;
+    }
+
+    tokio::runtime::Runtime::new()?.block_on(run(config_path, config, socket))
+}
+
+async fn run(
+    config_path: PathBuf,
+    config: Config,
+    socket: Option<std::os::unix::net::UnixListener>,
+) -> Result<()> {
+    let (config_tx, config_rx) = watch::channel(Arc::new(config));
+    let reloader = Arc::new(Reloader {
+        path: config_path,
+        config_tx,
     });
 
-    let (tx, rx) = channel::<Msg>(32);
+    let (events, events_rx) = unbounded_channel();
+    let (firewall_tx, firewall_rx) = unbounded_channel();
+    let (wireguard_tx, wireguard_rx) = unbounded_channel();
+    let (rule_tx, rule_rx) = unbounded_channel();
+    let (networkd_tx, networkd_rx) = unbounded_channel();
+    let n_handle = networkd::setup(networkd_rx, config_rx.clone())?;
+    let r_handle = rule::setup(rule_rx, config_rx.clone())?;
+    let wg_handle = wireguard::setup(wireguard_rx, config_rx.clone(), events.clone());
+    let f_handle = firewall::setup(firewall_rx, config_rx.clone());
 
-    let n_handle = networkd::setup(tx.subscribe(), config.clone())?;
-    let r_handle = rule::setup(rx, config.clone());
-    let w_handle = wifi::setup(tx.clone(), config.clone())?;
-    let wg_handle = wireguard::setup(tx.subscribe(), config.clone());
+    let (state_tx, state_rx) = watch::channel(state::State::Disconnected);
+    let links = links::Links::new(events.clone());
+    let s_handle = state::setup(
+        events_rx,
+        state::Subsystems {
+            firewall: firewall_tx,
+            wireguard: wireguard_tx,
+            rule: rule_tx,
+            networkd: networkd_tx,
+        },
+        hooks::Hooks::new(links.clone(), config_rx.clone()),
+        config_rx.clone(),
+        state_tx,
+    );
 
-    let done = Arc::new(AtomicBool::new(true));
+    let metrics_address = config_rx.borrow().metrics_address.clone();
+    let m_handle = metrics_address
+        .map(|address| metrics::setup(&address, state_rx.clone()))
+        .transpose()?;
+    let control = control::Control::new(
+        events.clone(),
+        links.clone(),
+        state_rx,
+        config_rx.clone(),
+        reloader.clone(),
+    );
+    let status_handle = systemd::setup_status(control.subscribe());
+    let c_handle = control::setup(control.clone(), socket)?;
+    let b_handle = bus::setup(control.clone());
+    let (notices, notify_handle) = notify::setup(control, config_rx.clone());
+    let (w_handle, wifi_ready) = wifi::setup(links.clone(), config_rx.clone(), notices)?;
+    let e_handle = ethernet::setup(links, config_rx)?;
+    let pause_handle = setup_pause(events.clone())?;
 
-    let d = done.clone();
-    ctrlc::set_handler(move || {
-        d.store(false, Ordering::SeqCst);
-    })
-    .context("failed to set ctrlc handler")?;
+    let mut hangup = signal(SignalKind::hangup()).context("failed to set SIGHUP handler")?;
+    let mut interrupt = signal(SignalKind::interrupt()).context("failed to set SIGINT handler")?;
+    let mut terminate = signal(SignalKind::terminate()).context("failed to set SIGTERM handler")?;
+
+    // Not knowing the networks yet shouldn't keep the rest from starting
+    if timeout(Duration::from_secs(10), wifi_ready).await.is_err() {
+        log::warn!("still looking up the current networks");
+    }
+    systemd::notify("READY=1");
 
-    while done.load(Ordering::SeqCst) {
-        sleep(Duration::from_millis(100)).await;
-        tokio::task::yield_now().await;
+    let mut watchdog = systemd::Watchdog::new();
+    let mut starving = false;
+    loop {
+        tokio::select! {
+            _ = interrupt.recv() => break,
+            _ = terminate.recv() => break,
+            _ = hangup.recv() => {
+                if let Err(e) = reloader.reload() {
+                    log::error!("failed to reload config, keeping the old one: {:#}", e);
+                }
+            }
+            _ = watchdog.tick() => {
+                let tasks = [
+                    &n_handle, &r_handle, &wg_handle, &f_handle, &w_handle, &e_handle, &c_handle,
+                ];
+                if !s_handle.is_finished() && tasks.iter().all(|t| !t.is_finished()) {
+                    systemd::notify("WATCHDOG=1");
+                } else if !starving {
+                    log::error!("a subsystem stopped, no longer feeding the watchdog");
+                    starving = true;
+                }
+            }
+        }
     }
 
+    systemd::notify("STOPPING=1");
+    pause_handle.abort();
+    c_handle.abort();
+    b_handle.abort();
+    notify_handle.abort();
+    status_handle.abort();
+    if let Some(handle) = m_handle {
+        handle.abort();
+    }
+    control::cleanup();
     w_handle.abort();
-    wg_handle.abort();
-    tx.send(Msg::Disable)?;
-    tx.send(Msg::Quit)?;
-    n_handle.await?;
-    r_handle.await?;
+    e_handle.abort();
+
+    let limit = Duration::from_secs(reloader.config_tx.borrow().shutdown_timeout);
+    shutdown(
+        events,
+        s_handle,
+        vec![
+            (state::Subsystem::Networkd, n_handle),
+            (state::Subsystem::Rule, r_handle),
+            (state::Subsystem::Wireguard, wg_handle),
+            (state::Subsystem::Firewall, f_handle),
+        ],
+        limit,
+    )
+    .await
+}
+
+/// Tears everything down, giving up on whatever isn't done after `limit`, and reports how it went
+/// for each subsystem. Fails unless every one of them is known to have cleaned up.
+async fn shutdown(
+    events: UnboundedSender<state::Event>,
+    mut s_handle: JoinHandle<state::Teardown>,
+    subsystems: Vec<(state::Subsystem, JoinHandle<()>)>,
+    limit: Duration,
+) -> Result<()> {
+    // The state machine keeps to the deadline itself, this is in case it is stuck
+    let grace = Duration::from_secs(1);
+    let deadline = Instant::now() + limit + grace;
+
+    let _ = events.send(state::Event::Shutdown);
+    let report = match timeout_at(deadline, &mut s_handle).await {
+        Ok(Ok(report)) => Some(report),
+        Ok(Err(e)) => {
+            log::error!("state machine failed: {}", e);
+            None
+        }
+        Err(_) => {
+            log::error!("state machine didn't finish tearing down in time");
+            s_handle.abort();
+            None
+        }
+    };
+
+    // They stop once the state machine is gone, unless they are stuck
+    let mut unconfirmed = Vec::new();
+    for (subsystem, mut handle) in subsystems {
+        let stopped = match timeout(grace, &mut handle).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(format!("crashed: {}", e)),
+            Err(_) => {
+                handle.abort();
+                Err(String::from("didn't stop"))
+            }
+        };
+        let cleaned = match &report {
+            Some(report) => match report.iter().find(|(s, _)| *s == subsystem) {
+                Some((_, result)) => result.clone().map(|()| "cleaned up"),
+                None => Ok("nothing to clean up"),
+            },
+            None => Err(String::from("unknown whether it cleaned up")),
+        };
+
+        match stopped.and(cleaned) {
+            Ok(outcome) => log::info!("{}: {}", subsystem.name(), outcome),
+            Err(e) => {
+                log::error!("{}: {}", subsystem.name(), e);
+                unconfirmed.push(subsystem.name());
+            }
+        }
+    }
+
+    if !unconfirmed.is_empty() {
+        anyhow::bail!(
+            "couldn't confirm that {} cleaned up",
+            unconfirmed.join(", ")
+        );
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn parses_arguments() {
+        Args::command().debug_assert();
+
+        let args = [
+            "autovpn",
+            "--foreground",
+            "--log-level",
+            "debug",
+            "check-config",
+        ];
+        let args = Args::try_parse_from(args).unwrap();
+        assert!(!args.daemon);
+        assert_eq!(args.log_level.as_deref(), Some("debug"));
+        assert!(matches!(args.command, Some(Command::CheckConfig)));
+
+        assert!(
+            Args::try_parse_from(["autovpn", "--daemon"])
+                .unwrap()
+                .daemon
+        );
+        assert!(Args::try_parse_from(["autovpn", "--daemon", "--foreground"]).is_err());
+
+        let help = Args::command().render_help().to_string();
+        assert!(help.contains("SIGUSR1"));
+    }
+}
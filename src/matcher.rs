@@ -0,0 +1,198 @@
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+
+use std::fmt;
+
+/// Matches an SSID against a pattern from the config.
+///
+/// A pattern is a glob like `corp-*` if it contains any of `*`, `?` or `[`, a regular expression
+/// if prefixed with `regex:`, and an exact SSID otherwise. `glob:` can be put in front of a glob
+/// to make that explicit, and an SSID that contains one of those characters itself is matched
+/// exactly by putting it in brackets, like `Cafe[?]`. Every form is compiled to an anchored regex
+/// when the config is loaded, so invalid patterns are rejected up front.
+#[derive(Clone, Debug)]
+pub struct SsidMatcher {
+    pattern: String,
+    ignore_case: bool,
+    regex: Regex,
+}
+
+impl SsidMatcher {
+    pub fn new(pattern: &str, ignore_case: bool) -> Result<Self, String> {
+        let source = if let Some(re) = pattern.strip_prefix("regex:") {
+            format!("^(?:{})$", re)
+        } else if let Some(glob) = pattern.strip_prefix("glob:") {
+            glob_to_regex(glob)?
+        } else if pattern.contains(['*', '?', '[']) {
+            glob_to_regex(pattern)?
+        } else {
+            format!("^{}$", regex::escape(pattern))
+        };
+
+        let regex = RegexBuilder::new(&source)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|e| format!("invalid pattern '{}': {}", pattern, e))?;
+
+        Ok(SsidMatcher {
+            pattern: pattern.to_string(),
+            ignore_case,
+            regex,
+        })
+    }
+
+    pub fn is_match(&self, ssid: &str) -> bool {
+        self.regex.is_match(ssid)
+    }
+}
+
+/// The regex is built from the pattern, so it is left out.
+impl PartialEq for SsidMatcher {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern && self.ignore_case == other.ignore_case
+    }
+}
+
+impl Eq for SsidMatcher {}
+
+impl fmt::Display for SsidMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+impl<'de> Deserialize<'de> for SsidMatcher {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        SsidMatcher::new(&pattern, false).map_err(serde::de::Error::custom)
+    }
+}
+
+/// The `known_networks` entry that matches exactly `ssid`. Whoever runs a network picks its SSID,
+/// so one that looks like a pattern is escaped instead of trusting everything it would match.
+pub fn literal(ssid: &str) -> String {
+    if ssid.starts_with("glob:") || ssid.starts_with("regex:") || ssid.contains(['*', '?', '[']) {
+        format!("regex:^{}$", regex::escape(ssid))
+    } else {
+        ssid.to_string()
+    }
+}
+
+fn glob_to_regex(glob: &str) -> Result<String, String> {
+    let mut re = String::from("^");
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            '[' => {
+                re.push('[');
+                if chars.next_if_eq(&'!').is_some() {
+                    re.push('^');
+                }
+
+                // a ']' right after the opening bracket is part of the class
+                let mut first = true;
+                loop {
+                    match chars.next() {
+                        Some(']') if !first => break,
+                        Some(c) => {
+                            if matches!(c, '\\' | '[' | ']' | '^' | '&' | '~') {
+                                re.push('\\');
+                            }
+                            re.push(c);
+                        }
+                        None => return Err(format!("unterminated '[' in pattern '{}'", glob)),
+                    }
+                    first = false;
+                }
+
+                re.push(']');
+            }
+            c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+
+    re.push('$');
+    Ok(re)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_entries_match_exactly() {
+        let matcher = SsidMatcher::new("Home", false).unwrap();
+        assert!(matcher.is_match("Home"));
+        assert!(!matcher.is_match("Home-5G"));
+        assert!(!matcher.is_match("home"));
+
+        let matcher = SsidMatcher::new("Home.+", false).unwrap();
+        assert!(matcher.is_match("Home.+"));
+        assert!(!matcher.is_match("Home-5G"));
+
+        let matcher = SsidMatcher::new("Cafe[?]", false).unwrap();
+        assert!(matcher.is_match("Cafe?"));
+        assert!(!matcher.is_match("Cafe1"));
+    }
+
+    #[test]
+    fn bare_globs() {
+        let matcher = SsidMatcher::new("corp-*", false).unwrap();
+        assert!(matcher.is_match("corp-5G-floor3"));
+        assert!(!matcher.is_match("guest-corp-5G"));
+
+        let matcher = SsidMatcher::new("Cafe?", false).unwrap();
+        assert!(matcher.is_match("Cafe1"));
+        assert!(!matcher.is_match("Cafe"));
+
+        assert!(SsidMatcher::new("[Home", false).is_err());
+    }
+
+    #[test]
+    fn globs() {
+        let matcher = SsidMatcher::new("glob:Cafe?", false).unwrap();
+        assert!(matcher.is_match("Cafe1"));
+        assert!(!matcher.is_match("Cafe12"));
+
+        let matcher = SsidMatcher::new("glob:Office-*", false).unwrap();
+        assert!(matcher.is_match("Office-"));
+        assert!(matcher.is_match("Office-3rd floor"));
+        assert!(!matcher.is_match("office-3"));
+
+        let matcher = SsidMatcher::new("glob:Lab[!0-9]", false).unwrap();
+        assert!(matcher.is_match("LabA"));
+        assert!(!matcher.is_match("Lab1"));
+
+        let matcher = SsidMatcher::new("glob:[]x]", false).unwrap();
+        assert!(matcher.is_match("]"));
+
+        assert!(SsidMatcher::new("glob:Lab[", false).is_err());
+    }
+
+    #[test]
+    fn regexes_and_case() {
+        let matcher = SsidMatcher::new("regex:Guest-[0-9]+", false).unwrap();
+        assert!(matcher.is_match("Guest-42"));
+        assert!(!matcher.is_match("xGuest-42"));
+
+        let matcher = SsidMatcher::new("glob:home*", true).unwrap();
+        assert!(matcher.is_match("HOME-5G"));
+
+        assert!(SsidMatcher::new("regex:(", false).is_err());
+    }
+
+    #[test]
+    fn literals_only_match_themselves() {
+        assert_eq!(literal("Home"), "Home");
+
+        for ssid in ["regex:.*", "glob:*", "regex:^$", "*", "Cafe?", "[Home"] {
+            let matcher = SsidMatcher::new(&literal(ssid), false).unwrap();
+            assert!(matcher.is_match(ssid));
+            assert!(!matcher.is_match("Home"));
+            assert!(!matcher.is_match(""));
+        }
+    }
+}
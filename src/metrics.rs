@@ -0,0 +1,249 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration, Instant};
+
+use log::*;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+use std::time::SystemTime;
+
+use super::control;
+use super::state::{State, StateRx, KINDS};
+use super::wireguard;
+
+/// Where to serve the metrics, a TCP address such as `127.0.0.1:9586` or the path of a unix
+/// socket.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl TryFrom<String> for Address {
+    type Error = String;
+
+    fn try_from(address: String) -> Result<Self, Self::Error> {
+        if address.starts_with('/') {
+            return Ok(Address::Unix(PathBuf::from(address)));
+        }
+
+        address
+            .parse()
+            .map(Address::Tcp)
+            .map_err(|_| format!("'{}' is neither an address with a port nor a path", address))
+    }
+}
+
+struct Metrics {
+    state: &'static str,
+    since: Instant,
+    /// Time spent in each state, without the time since entering the current one.
+    time_in: BTreeMap<&'static str, Duration>,
+    /// Keyed by the state that was reached and what caused it.
+    transitions: BTreeMap<(&'static str, &'static str), u64>,
+    /// Keyed by subsystem.
+    errors: BTreeMap<&'static str, u64>,
+}
+
+static METRICS: LazyLock<Mutex<Metrics>> = LazyLock::new(|| {
+    Mutex::new(Metrics {
+        state: "disconnected",
+        since: Instant::now(),
+        time_in: BTreeMap::new(),
+        transitions: BTreeMap::new(),
+        errors: BTreeMap::new(),
+    })
+});
+
+/// Counts a netlink or D-Bus error of `subsystem`.
+pub fn error(subsystem: &'static str) {
+    *METRICS.lock().unwrap().errors.entry(subsystem).or_default() += 1;
+}
+
+/// Counts reaching `state` because of `reason`.
+pub fn transition(state: &State, reason: &'static str) {
+    let mut metrics = METRICS.lock().unwrap();
+    *metrics
+        .transitions
+        .entry((state.kind(), reason))
+        .or_default() += 1;
+}
+
+/// Records that the state machine is now in `state`, for the time spent in each state.
+pub fn enter(state: &State) {
+    let mut metrics = METRICS.lock().unwrap();
+    if metrics.state == state.kind() {
+        return;
+    }
+
+    let now = Instant::now();
+    let spent = now - metrics.since;
+    let previous = metrics.state;
+    *metrics.time_in.entry(previous).or_default() += spent;
+    metrics.state = state.kind();
+    metrics.since = now;
+}
+
+/// Renders the metrics in the Prometheus text format.
+async fn render(state: &State) -> String {
+    // Asking the kernel for the handshake may take a moment, so don't hold the lock meanwhile
+    let handshake = match state.profile() {
+        Some(profile) => {
+            let ifname = profile.wireguard_interface.clone();
+            match wireguard::get_device(&ifname).await {
+                Ok(device) => Some((ifname, device.last_handshake())),
+                Err(e) => {
+                    debug!("failed to get last handshake of {}: {:#}", ifname, e);
+                    error("wireguard");
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+
+    let metrics = METRICS.lock().unwrap();
+    let mut out = String::new();
+
+    out.push_str("# HELP autovpn_state Whether autovpn is in each state.\n");
+    out.push_str("# TYPE autovpn_state gauge\n");
+    for kind in KINDS {
+        let value = u8::from(kind == metrics.state);
+        let _ = writeln!(out, "autovpn_state{{state=\"{}\"}} {}", kind, value);
+    }
+
+    out.push_str("# HELP autovpn_state_seconds_total Time spent in each state.\n");
+    out.push_str("# TYPE autovpn_state_seconds_total counter\n");
+    for kind in KINDS {
+        let mut spent = metrics.time_in.get(kind).copied().unwrap_or_default();
+        if kind == metrics.state {
+            spent += metrics.since.elapsed();
+        }
+        let _ = writeln!(
+            out,
+            "autovpn_state_seconds_total{{state=\"{}\"}} {:.3}",
+            kind,
+            spent.as_secs_f64()
+        );
+    }
+
+    out.push_str("# HELP autovpn_transitions_total State changes by the state reached and why.\n");
+    out.push_str("# TYPE autovpn_transitions_total counter\n");
+    for ((state, reason), count) in metrics.transitions.iter() {
+        let _ = writeln!(
+            out,
+            "autovpn_transitions_total{{state=\"{}\",reason=\"{}\"}} {}",
+            state, reason, count
+        );
+    }
+
+    out.push_str("# HELP autovpn_errors_total Netlink and D-Bus errors by subsystem.\n");
+    out.push_str("# TYPE autovpn_errors_total counter\n");
+    for subsystem in ["firewall", "networkd", "rule", "wifi", "wireguard"] {
+        let count = metrics.errors.get(subsystem).copied().unwrap_or_default();
+        let _ = writeln!(
+            out,
+            "autovpn_errors_total{{subsystem=\"{}\"}} {}",
+            subsystem, count
+        );
+    }
+
+    // Left out while the tunnel is down or before the first handshake
+    if let Some((ifname, Some(time))) = handshake {
+        let age = SystemTime::now()
+            .duration_since(time)
+            .unwrap_or_default()
+            .as_secs();
+        out.push_str(
+            "# HELP autovpn_wireguard_last_handshake_age_seconds Time since the last handshake.\n",
+        );
+        out.push_str("# TYPE autovpn_wireguard_last_handshake_age_seconds gauge\n");
+        let _ = writeln!(
+            out,
+            "autovpn_wireguard_last_handshake_age_seconds{{interface=\"{}\"}} {}",
+            ifname, age
+        );
+    }
+
+    out
+}
+
+/// Answers any request with the metrics, there is only the one page.
+async fn serve<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, state: State) -> Result<()> {
+    // Read until the end of the request head, whatever it asked for
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = timeout(Duration::from_secs(5), stream.read(&mut buf)).await??;
+        if n == 0 || request.len() > 16 * 1024 {
+            anyhow::bail!("incomplete request");
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let body = render(&state).await;
+    let response = format!(
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn answer<S>(stream: S, state: &StateRx)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let state = state.borrow().clone();
+    tokio::spawn(async move {
+        if let Err(e) = serve(stream, state).await {
+            debug!("metrics request failed: {:#}", e);
+        }
+    });
+}
+
+pub fn setup(address: &Address, state: StateRx) -> Result<JoinHandle<()>> {
+    enum Listener {
+        Tcp(TcpListener),
+        Unix(UnixListener),
+    }
+
+    let listener = match address {
+        Address::Tcp(addr) => {
+            let listener = std::net::TcpListener::bind(addr)
+                .with_context(|| format!("failed to bind metrics to {}", addr))?;
+            listener.set_nonblocking(true)?;
+            Listener::Tcp(TcpListener::from_std(listener)?)
+        }
+        Address::Unix(path) => {
+            control::remove_stale_socket(path)?;
+            let listener = UnixListener::bind(path)
+                .with_context(|| format!("failed to bind metrics to {}", path.display()))?;
+            Listener::Unix(listener)
+        }
+    };
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let result = match &listener {
+                Listener::Tcp(l) => l.accept().await.map(|(s, _)| answer(s, &state)),
+                Listener::Unix(l) => l.accept().await.map(|(s, _)| answer(s, &state)),
+            };
+
+            if let Err(e) = result {
+                error!("failed to accept metrics connection: {}", e);
+            }
+        }
+    }))
+}
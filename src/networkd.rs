@@ -1,16 +1,18 @@
-use super::{Config, Msg};
+use super::config::Dns;
+use super::state::{Command, Request};
+use super::{metrics, Config, ConfigRx, Profile};
 
 use anyhow::{Context, Result};
 
+use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
 use dbus::nonblock::{Proxy, SyncConnection};
 use dbus_tokio::connection;
 
 use log::*;
 
-use tokio::sync::broadcast::Receiver;
+use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::task::JoinHandle;
 
-use std::sync::Arc;
 use std::time::Duration;
 
 fn get_network_proxy(conn: &SyncConnection) -> Proxy<'static, &SyncConnection> {
@@ -41,60 +43,182 @@ async fn set_domains<'a>(
     domains: &[&str],
 ) -> Result<()> {
     let domains = domains.iter().map(|s| (*s, true)).collect::<Vec<_>>();
-    Ok(proxy
+    proxy
         .method_call(
             "org.freedesktop.network1.Manager",
             "SetLinkDomains",
             (ifindex, domains),
         )
         .await
-        .context("failed to set link domains")?)
+        .context("failed to set link domains")
 }
 
-async fn enable_dns(conn: &SyncConnection, ifname: &str) -> Result<()> {
+/// Reads the domains of a link back from resolved, which is where networkd hands them to.
+async fn get_domains(conn: &SyncConnection, ifindex: i32) -> Result<Vec<(String, bool)>> {
+    let resolve = Proxy::new(
+        "org.freedesktop.resolve1",
+        "/org/freedesktop/resolve1",
+        Duration::from_secs(2),
+        conn,
+    );
+    let (path,): (dbus::Path,) = resolve
+        .method_call("org.freedesktop.resolve1.Manager", "GetLink", (ifindex,))
+        .await
+        .context("failed to get resolved link")?;
+
+    Proxy::new(
+        "org.freedesktop.resolve1",
+        path,
+        Duration::from_secs(2),
+        conn,
+    )
+    .get("org.freedesktop.resolve1.Link", "Domains")
+    .await
+    .context("failed to get link domains")
+}
+
+/// The routing domains set on the WireGuard interface of `profile`, `None` if they are left alone.
+fn profile_domains(profile: &Profile) -> Option<Vec<&str>> {
+    match &profile.dns {
+        Dns::Tunnel => Some(vec![""]),
+        Dns::Domains(domains) => Some(domains.iter().map(String::as_str).collect()),
+        Dns::System => None,
+    }
+}
+
+async fn enable_dns(conn: &SyncConnection, profile: &Profile) -> Result<()> {
+    let Some(domains) = profile_domains(profile) else {
+        return Ok(());
+    };
+
     let proxy = get_network_proxy(conn);
-    let ifindex = get_ifindex(&proxy, ifname).await?;
-    set_domains(&proxy, ifindex, &[""]).await?;
-    debug!("changed dns domain to ~.");
+    let ifindex = get_ifindex(&proxy, &profile.wireguard_interface).await?;
+    set_domains(&proxy, ifindex, &domains).await?;
+    debug!("changed dns domains to {:?}", profile.dns);
     Ok(())
 }
 
-async fn disable_dns(conn: &SyncConnection, ifname: &str) -> Result<()> {
+async fn disable_dns(conn: &SyncConnection, profile: &Profile) -> Result<()> {
+    if profile.dns == Dns::System {
+        return Ok(());
+    }
+
     let proxy = get_network_proxy(conn);
-    let ifindex = get_ifindex(&proxy, ifname).await?;
+    let ifindex = get_ifindex(&proxy, &profile.wireguard_interface).await?;
     set_domains(&proxy, ifindex, &[]).await?;
     debug!("removed dns domains");
     Ok(())
 }
 
-pub fn setup(mut rx: Receiver<Msg>, config: Arc<Config>) -> Result<JoinHandle<()>> {
+async fn run(conn: &SyncConnection, command: Command, config: &ConfigRx) -> Result<()> {
+    match command {
+        Command::Enable { old, new } => {
+            // Setting the domains replaces the old ones, so only clear them if they were set on
+            // another link or shouldn't be set at all
+            if let Some(old) = old.filter(|old| {
+                old.wireguard_interface != new.wireguard_interface || new.dns == Dns::System
+            }) {
+                disable_dns(conn, &old).await?;
+            }
+
+            enable_dns(conn, &new).await
+        }
+        Command::Disable(old) => {
+            // Without a known active profile, clean up after all of them
+            let profiles = match old {
+                Some(profile) => vec![profile],
+                None => config.borrow().all_profiles().cloned().collect(),
+            };
+
+            for profile in profiles {
+                disable_dns(conn, &profile).await?;
+            }
+            Ok(())
+        }
+        Command::Reconcile(profile) => {
+            let config = config.borrow().clone();
+            reconcile_dns(conn, profile.as_deref(), &config)
+                .await
+                .context("failed to reconcile dns")
+        }
+    }
+}
+
+/// Sets the domains of `profile` back if they changed and removes those set on the interfaces of
+/// the other profiles, logging whatever had drifted.
+async fn reconcile_dns(
+    conn: &SyncConnection,
+    profile: Option<&Profile>,
+    config: &Config,
+) -> Result<()> {
+    let proxy = get_network_proxy(conn);
+
+    if let Some(profile) = profile {
+        if let Some(expected) = profile_domains(profile) {
+            let ifindex = get_ifindex(&proxy, &profile.wireguard_interface).await?;
+
+            // resolved reports the root domain as "."
+            let mut current = get_domains(conn, ifindex)
+                .await?
+                .into_iter()
+                .map(|(d, route)| (d.trim_end_matches('.').to_string(), route))
+                .collect::<Vec<_>>();
+            let mut wanted = expected
+                .iter()
+                .map(|d| (d.trim_end_matches('.').to_string(), true))
+                .collect::<Vec<_>>();
+            current.sort();
+            wanted.sort();
+
+            if current != wanted {
+                warn!(
+                    "dns domains of {} changed to {:?}, setting them back",
+                    profile.wireguard_interface, current
+                );
+                set_domains(&proxy, ifindex, &expected).await?;
+            }
+        }
+    }
+
+    for other in config.all_profiles() {
+        let in_use = profile.is_some_and(|p| p.wireguard_interface == other.wireguard_interface);
+        if in_use || other.dns == Dns::System {
+            continue;
+        }
+
+        // The interfaces of profiles that were never used may not exist at all
+        let Ok(ifindex) = get_ifindex(&proxy, &other.wireguard_interface).await else {
+            continue;
+        };
+        let current = get_domains(conn, ifindex).await?;
+        if !current.is_empty() {
+            warn!(
+                "found dns domains {:?} on {} which isn't in use, removing them",
+                current, other.wireguard_interface
+            );
+            set_domains(&proxy, ifindex, &[]).await?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn setup(mut rx: UnboundedReceiver<Request>, config: ConfigRx) -> Result<JoinHandle<()>> {
     let (resource, conn) = connection::new_system_sync()?;
     debug!("got dbus connection");
 
     let err_handle = tokio::spawn(async {
         let err = resource.await;
         error!("lost system dbus connection: {}", err);
+        metrics::error("networkd");
     });
 
     let handle = tokio::spawn(async move {
-        while let Ok(m) = rx.recv().await {
-            match m {
-                Msg::Enable => {
-                    if let Err(e) = enable_dns(&conn, &config.wireguard_interface).await {
-                        error!("error on dns enable: {}", e);
-                    }
-                }
-                Msg::Disable => {
-                    if let Err(e) = disable_dns(&conn, &config.wireguard_interface).await {
-                        error!("error on dns disable: {}", e);
-                    }
-                }
-                Msg::Quit => {
-                    err_handle.abort();
-                    break;
-                }
-            }
+        while let Some((command, ack)) = rx.recv().await {
+            let _ = ack.send(run(&conn, command, &config).await);
         }
+
+        err_handle.abort();
     });
 
     Ok(handle)
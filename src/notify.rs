@@ -0,0 +1,368 @@
+use anyhow::{Context, Result};
+use clap::Subcommand;
+
+use dbus::arg::PropMap;
+use dbus::blocking::Connection;
+use dbus::message::MatchRule;
+use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
+use dbus::nonblock::{Proxy, SyncConnection};
+use dbus_tokio::connection;
+
+use tokio::process::Command;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::task::JoinHandle;
+
+use log::*;
+
+use std::fmt;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::control::{Control, Request};
+use super::state::State;
+use super::ConfigRx;
+
+const LOGIND: &str = "org.freedesktop.login1";
+const NOTIFICATIONS: &str = "org.freedesktop.Notifications";
+
+/// The actions offered on a notification, as the key sent back and the label shown.
+const TRUST: (&str, &str) = ("trust", "Trust this network");
+const PAUSE: (&str, &str) = ("pause", "Pause VPN 1h");
+
+/// What a notification is about, which is also how the helper is told.
+#[derive(Clone, Debug, PartialEq, Eq, Subcommand)]
+pub enum Notice {
+    /// An unknown network was connected to
+    Untrusted { ssid: String },
+    /// The tunnel of a profile gets no handshake
+    Unhealthy { profile: String },
+}
+
+impl Notice {
+    /// The arguments that tell the helper about this notice. Whoever runs a network picks its
+    /// SSID, so it must never be taken for an option.
+    fn args(&self) -> [&str; 3] {
+        match self {
+            Notice::Untrusted { ssid } => ["untrusted", "--", ssid],
+            Notice::Unhealthy { profile } => ["unhealthy", "--", profile],
+        }
+    }
+
+    fn summary(&self) -> &'static str {
+        match self {
+            Notice::Untrusted { .. } => "Untrusted network",
+            Notice::Unhealthy { .. } => "VPN not connecting",
+        }
+    }
+
+    fn body(&self) -> String {
+        match self {
+            Notice::Untrusted { ssid } => format!(
+                "Connected to the unknown network '{}', the VPN is being turned on.",
+                ssid
+            ),
+            Notice::Unhealthy { profile } => format!(
+                "The VPN server of profile '{}' isn't answering, traffic may not get through.",
+                profile
+            ),
+        }
+    }
+
+    fn actions(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Notice::Untrusted { .. } => &[TRUST, PAUSE],
+            Notice::Unhealthy { .. } => &[PAUSE],
+        }
+    }
+}
+
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Notice::Untrusted { ssid } => write!(f, "unknown network '{}'", ssid),
+            Notice::Unhealthy { profile } => write!(f, "unhealthy tunnel of profile '{}'", profile),
+        }
+    }
+}
+
+/// The id, uid, user name, seat and object path of a session, as listed by logind.
+type Session<'a> = (String, u32, String, String, dbus::Path<'a>);
+
+fn get_proxy<'a>(conn: &'a SyncConnection, path: dbus::Path<'a>) -> Proxy<'a, &'a SyncConnection> {
+    Proxy::new(LOGIND, path, Duration::from_secs(2), conn)
+}
+
+/// The uid of whoever sits in front of the machine, i.e. the owner of the active local graphical
+/// session, if anyone does.
+async fn active_user(conn: &SyncConnection) -> Result<Option<u32>> {
+    let manager = get_proxy(conn, dbus::Path::from("/org/freedesktop/login1"));
+    let (sessions,): (Vec<Session>,) = manager
+        .method_call("org.freedesktop.login1.Manager", "ListSessions", ())
+        .await
+        .context("failed to list sessions")?;
+
+    for (_, uid, _, _, path) in sessions {
+        let session = get_proxy(conn, path);
+        let interface = "org.freedesktop.login1.Session";
+        let active: bool = session.get(interface, "Active").await?;
+        let remote: bool = session.get(interface, "Remote").await?;
+        let kind: String = session.get(interface, "Type").await?;
+
+        if active && !remote && (kind == "x11" || kind == "wayland") {
+            return Ok(Some(uid));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Shows `notice` as the active user and waits for the action they pick.
+async fn ask(notice: &Notice) -> Result<Option<String>> {
+    let (resource, conn) = connection::new_system_sync()?;
+    let resource = tokio::spawn(async {
+        let err = resource.await;
+        debug!("lost system dbus connection: {}", err);
+    });
+    let uid = active_user(&conn).await;
+    resource.abort();
+
+    let Some(uid) = uid? else {
+        debug!("nobody is logged in to notify about {}", notice);
+        return Ok(None);
+    };
+    let user = nix::unistd::User::from_uid(uid.into())?
+        .with_context(|| format!("no user with uid {}", uid))?;
+    let bus = format!("/run/user/{}/bus", uid);
+    if !Path::new(&bus).exists() {
+        debug!(
+            "{} has no session bus to notify about {}",
+            user.name, notice
+        );
+        return Ok(None);
+    }
+
+    // A user's session bus only lets that user in, so let the helper do the talking as them
+    let output = Command::new(std::env::current_exe()?)
+        .arg("notify")
+        .args(notice.args())
+        .env("DBUS_SESSION_BUS_ADDRESS", format!("unix:path={}", bus))
+        .env("HOME", &user.dir)
+        .uid(user.uid.as_raw())
+        .gid(user.gid.as_raw())
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("failed to run notification helper")?;
+    if !output.status.success() {
+        anyhow::bail!("notification helper {}", output.status);
+    }
+
+    let action = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok((!action.is_empty()).then_some(action))
+}
+
+/// Does what was picked on the notification about `notice`.
+fn act(control: &Control, notice: &Notice, action: &str) -> Result<()> {
+    let result = match (action, notice) {
+        // Only ever the network that is named like this, even if that looks like a pattern
+        ("trust", Notice::Untrusted { ssid }) => control.trust(ssid),
+        ("pause", _) => control.handle(Request::Pause { minutes: 60 }),
+        (action, _) => anyhow::bail!("unknown action '{}'", action),
+    };
+
+    result.map(|_| ())
+}
+
+async fn notify(control: &Control, notice: &Notice) -> Result<()> {
+    match ask(notice).await? {
+        Some(action) => act(control, notice, &action),
+        None => Ok(()),
+    }
+}
+
+/// Notifies the active user whenever an unknown network is connected to, as sent on the returned
+/// sender, and whenever the tunnel turns unhealthy. Only the latest notification is followed up
+/// on, and none are shown unless `notifications` is set.
+pub fn setup(control: Arc<Control>, config: ConfigRx) -> (UnboundedSender<Notice>, JoinHandle<()>) {
+    let (tx, mut rx) = unbounded_channel::<Notice>();
+    let mut state = control.subscribe();
+
+    let handle = tokio::spawn(async move {
+        let mut current: Option<(Notice, JoinHandle<()>)> = None;
+        let mut unhealthy = false;
+
+        loop {
+            let notice = tokio::select! {
+                notice = rx.recv() => match notice {
+                    Some(notice) => notice,
+                    None => break,
+                },
+                changed = state.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    let profile = match &*state.borrow_and_update() {
+                        State::Unhealthy(tunnel) => tunnel.profile().map(|p| p.name.clone()),
+                        _ => None,
+                    };
+
+                    // Only once when it turns unhealthy, not again until it recovered
+                    let was = std::mem::replace(&mut unhealthy, profile.is_some());
+                    match profile {
+                        Some(profile) if !was => Notice::Unhealthy { profile },
+                        _ => continue,
+                    }
+                }
+            };
+
+            if !config.borrow().notifications {
+                continue;
+            }
+            // The same network is checked again when reloading, which shouldn't notify twice
+            if let Some((shown, handle)) = &current {
+                if *shown == notice && !handle.is_finished() {
+                    continue;
+                }
+                handle.abort();
+            }
+
+            let control = control.clone();
+            let n = notice.clone();
+            let handle = tokio::spawn(async move {
+                if let Err(e) = notify(&control, &n).await {
+                    error!("failed to notify about {}: {:#}", n, e);
+                }
+            });
+            current = Some((notice, handle));
+        }
+    });
+
+    (tx, handle)
+}
+
+/// Shows `notice` on the session bus and prints the key of the action that was picked, if any,
+/// once it is closed. This is what the daemon runs as the logged in user.
+pub fn show(notice: &Notice) -> Result<()> {
+    let conn = Connection::new_session().context("failed to connect to session bus")?;
+    let proxy = conn.with_proxy(
+        NOTIFICATIONS,
+        "/org/freedesktop/Notifications",
+        Duration::from_secs(5),
+    );
+
+    // Every notification that was closed, along with the action if one was picked. Watched for
+    // before showing ours so that the signals can't be missed.
+    let closed = Arc::new(Mutex::new(Vec::new()));
+
+    let c = closed.clone();
+    let rule = MatchRule::new_signal(NOTIFICATIONS, "ActionInvoked");
+    conn.add_match(rule, move |(id, key): (u32, String), _, _| {
+        c.lock().unwrap().push((id, Some(key)));
+        true
+    })?;
+    let c = closed.clone();
+    let rule = MatchRule::new_signal(NOTIFICATIONS, "NotificationClosed");
+    conn.add_match(rule, move |(id, _): (u32, u32), _, _| {
+        c.lock().unwrap().push((id, None));
+        true
+    })?;
+
+    let actions: Vec<&str> = notice
+        .actions()
+        .iter()
+        .flat_map(|(k, l)| [*k, *l])
+        .collect();
+    let (id,): (u32,) = proxy
+        .method_call(
+            NOTIFICATIONS,
+            "Notify",
+            (
+                "autovpn",
+                0u32,
+                "network-vpn",
+                notice.summary(),
+                notice.body(),
+                actions,
+                PropMap::new(),
+                -1i32,
+            ),
+        )
+        .context("failed to show notification")?;
+
+    // An action is followed by the notification closing, so the first one of ours decides
+    loop {
+        let found = closed
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(n, _)| *n == id)
+            .cloned();
+        if let Some((_, action)) = found {
+            if let Some(key) = action {
+                println!("{}", key);
+            }
+            return Ok(());
+        }
+        conn.process(Duration::from_secs(60))?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Event;
+    use crate::{config, links, Reloader};
+
+    use tokio::sync::watch;
+
+    const CONFIG: &str = r#"
+wireguard_interface = "wg0"
+wlan_interfaces = []
+known_networks = ["Home"]
+firewall_mark = 1
+routing_table = 2
+ipv6 = false
+"#;
+
+    #[tokio::test]
+    async fn trusting_only_trusts_that_network() {
+        let path = std::env::temp_dir().join(format!("autovpn-notify-{}.toml", std::process::id()));
+        std::fs::write(&path, CONFIG).unwrap();
+        let (config_tx, config_rx) = watch::channel(Arc::new(config::load(&path).unwrap()));
+        let (events, mut events_rx) = unbounded_channel();
+        let (_, state) = watch::channel(State::Disconnected);
+        let reloader = Arc::new(Reloader {
+            path: path.clone(),
+            config_tx,
+        });
+        let control = Control::new(
+            events.clone(),
+            links::Links::new(events),
+            state,
+            config_rx.clone(),
+            reloader,
+        );
+
+        for ssid in ["regex:.*", "glob:*"] {
+            let notice = Notice::Untrusted {
+                ssid: ssid.to_string(),
+            };
+            act(&control, &notice, "trust").unwrap();
+            assert!(config_rx.borrow().knows(ssid));
+        }
+        assert!(!config_rx.borrow().knows("Cafe"));
+
+        let notice = Notice::Unhealthy {
+            profile: String::from("default"),
+        };
+        act(&control, &notice, "pause").unwrap();
+        assert!(matches!(events_rx.try_recv(), Ok(Event::Pause(Some(_)))));
+        assert!(act(&control, &notice, "trust").is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,222 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpSocket;
+use tokio::time::{timeout, Duration};
+
+use log::*;
+
+use super::config::CaptivePortal;
+
+/// A plain `http://` URL, since a captive portal intercepts exactly those.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct HttpUrl {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl TryFrom<String> for HttpUrl {
+    type Error = String;
+
+    fn try_from(url: String) -> Result<Self, Self::Error> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("'{}' is not an http:// url", url))?;
+
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("invalid port in url '{}'", url))?;
+                (host, port)
+            }
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("no host in url '{}'", url));
+        }
+
+        Ok(HttpUrl {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// How long a single probe may take, a portal that doesn't answer is as good as one that
+/// redirects.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn get(url: &HttpUrl, ifname: &str) -> Result<(u16, String)> {
+    let addr = tokio::net::lookup_host((url.host.as_str(), url.port))
+        .await?
+        .next()
+        .with_context(|| format!("no address for {}", url.host))?;
+
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    // Go out through the link being checked rather than whatever the routes say, unless the
+    // probe is pointed at a local server
+    if !addr.ip().is_loopback() {
+        socket.bind_device(Some(ifname.as_bytes()))?;
+    }
+
+    let mut stream = socket.connect(addr).await?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: autovpn\r\nConnection: close\r\n\r\n",
+        url.path, url.host
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .context("malformed http response")?;
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .context("malformed http status line")?;
+
+    Ok((status, body.to_string()))
+}
+
+/// Checks whether the network behind `ifname` reaches the internet, rather than a captive portal
+/// that answers the probe with a login page or a redirect.
+pub async fn probe(portal: &CaptivePortal, ifname: &str) -> bool {
+    let (status, body) = match timeout(PROBE_TIMEOUT, get(&portal.url, ifname)).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => {
+            debug!("captive portal probe on {} failed: {:#}", ifname, e);
+            return false;
+        }
+        Err(_) => {
+            debug!("captive portal probe on {} timed out", ifname);
+            return false;
+        }
+    };
+
+    let passed = status == portal.expected_status
+        && portal
+            .expected_body
+            .as_ref()
+            .is_none_or(|expected| body.trim() == expected.trim());
+    if !passed {
+        debug!(
+            "captive portal probe on {} got an unexpected response with status {}",
+            ifname, status
+        );
+    }
+
+    passed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn url(s: &str) -> Result<HttpUrl, String> {
+        HttpUrl::try_from(s.to_string())
+    }
+
+    fn portal(url: HttpUrl, expected_status: u16, expected_body: Option<&str>) -> CaptivePortal {
+        CaptivePortal {
+            url,
+            expected_status,
+            expected_body: expected_body.map(String::from),
+            interval: 5,
+        }
+    }
+
+    /// Serves one connection on a local port, answering with `response` or never answering if
+    /// there is none.
+    async fn serve(response: Option<&'static str>) -> HttpUrl {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            assert!(request.starts_with(b"GET /generate_204 HTTP/1.0\r\n"));
+
+            match response {
+                Some(response) => stream.write_all(response.as_bytes()).await.unwrap(),
+                None => std::future::pending().await,
+            }
+        });
+
+        url(&format!("http://127.0.0.1:{}/generate_204", port)).unwrap()
+    }
+
+    #[test]
+    fn parses_urls() {
+        let parsed = url("http://example.com").unwrap();
+        assert_eq!(
+            (parsed.host.as_str(), parsed.port, parsed.path.as_str()),
+            ("example.com", 80, "/")
+        );
+
+        let parsed = url("http://example.com:8080/check?x=1").unwrap();
+        assert_eq!(
+            (parsed.host.as_str(), parsed.port, parsed.path.as_str()),
+            ("example.com", 8080, "/check?x=1")
+        );
+
+        assert!(url("https://example.com/").is_err());
+        assert!(url("http://example.com:http/").is_err());
+        assert!(url("http:///path").is_err());
+    }
+
+    #[tokio::test]
+    async fn no_content_means_no_portal() {
+        let url = serve(Some("HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")).await;
+        assert!(probe(&portal(url, 204, None), "lo").await);
+    }
+
+    #[tokio::test]
+    async fn redirect_means_portal() {
+        let url = serve(Some(
+            "HTTP/1.1 302 Found\r\nLocation: http://login.example/\r\n\r\n",
+        ))
+        .await;
+        assert!(!probe(&portal(url, 204, None), "lo").await);
+    }
+
+    #[tokio::test]
+    async fn login_page_means_portal() {
+        let url = serve(Some("HTTP/1.1 200 OK\r\n\r\n<html>Log in</html>")).await;
+        assert!(!probe(&portal(url, 204, None), "lo").await);
+
+        let url = serve(Some("HTTP/1.1 200 OK\r\n\r\n<html>Log in</html>")).await;
+        assert!(!probe(&portal(url, 200, Some("success")), "lo").await);
+
+        let url = serve(Some("HTTP/1.1 200 OK\r\n\r\nsuccess\n")).await;
+        assert!(probe(&portal(url, 200, Some("success")), "lo").await);
+    }
+
+    #[tokio::test]
+    async fn no_answer_means_portal() {
+        let url = serve(None).await;
+        assert!(!probe(&portal(url, 204, None), "lo").await);
+    }
+}
@@ -8,17 +8,20 @@ use neli::{
     },
     nl::{NlPayload, Nlmsghdr},
     rtnl::{Rtattr, Rtmsg},
+    socket::tokio::NlSocket,
     socket::NlSocketHandle,
     types::{Buffer, NlBuffer, RtBuffer},
 };
-use tokio::sync::broadcast::Receiver;
+use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Duration, Instant};
 
 use std::sync::Arc;
 
 use log::*;
 
-use super::{Config, Msg};
+use super::state::{Command, Request};
+use super::{metrics, Config, ConfigRx, Profile};
 
 fn generate_rtattrs(fwmark: u32, table: u32) -> RtBuffer<Rta, Buffer> {
     let mut buf = RtBuffer::new();
@@ -76,6 +79,13 @@ fn generate_msg_header(
     )
 }
 
+/// Waits for the kernel to acknowledge a request sent with [`NlmF::Ack`], which is the only way
+/// to learn that it failed.
+fn recv_ack(socket: &mut NlSocketHandle) -> Result<()> {
+    socket.recv::<Nlmsg, Buffer>()?;
+    Ok(())
+}
+
 fn add_rule(
     socket: &mut NlSocketHandle,
     family: RtAddrFamily,
@@ -89,26 +99,32 @@ fn add_rule(
             Rtm::Newrule,
             fwmark,
             table,
-            &[NlmF::Request, NlmF::Create, NlmF::Excl],
+            &[NlmF::Request, NlmF::Ack, NlmF::Create, NlmF::Excl],
         ))?;
+        recv_ack(socket).with_context(|| format!("failed to add {:?} rule", family))?;
     }
 
     Ok(())
 }
 
-fn check_rule(msg: &Rtmsg) -> bool {
+fn parse_u32(bytes: &[u8]) -> Option<u32> {
+    bytes.try_into().ok().map(u32::from_ne_bytes)
+}
+
+fn check_rule(msg: &Rtmsg, fwmark: u32, table: u32) -> bool {
+    let mut table_matches = false;
+    let mut fwmark_matches = false;
+
     for attr in msg.rtattrs.iter() {
+        let value = parse_u32(attr.rta_payload.as_ref());
         if attr.rta_type == Rta::Table {
-            let mut num: [u8; 4] = Default::default();
-            num.copy_from_slice(attr.rta_payload.as_ref());
-
-            if u32::from_ne_bytes(num) == 1000 {
-                return true;
-            }
+            table_matches = value == Some(table);
+        } else if attr.rta_type == Rta::Mark {
+            fwmark_matches = value == Some(fwmark);
         }
     }
 
-    false
+    table_matches && fwmark_matches
 }
 
 fn check_rules(
@@ -138,7 +154,7 @@ fn check_rules(
         }
 
         if let Some(payload) = msg.nl_payload.get_payload() {
-            if check_rule(payload) {
+            if check_rule(payload, fwmark, table) {
                 rule_exists = true;
             }
         }
@@ -170,22 +186,20 @@ fn remove_rule(
                 Rtm::Delrule,
                 fwmark,
                 table,
-                &[NlmF::Request],
+                &[NlmF::Request, NlmF::Ack],
             ))
             .context("failed to send msg")?;
+        recv_ack(socket).with_context(|| format!("failed to remove {:?} rule", family))?;
     }
     Ok(())
 }
 
-async fn enable_rules(config: Arc<Config>) -> Result<()> {
-    let config = config.clone();
-
-    let fwmark = config.firewall_mark;
-    let table = config.routing_table;
-    let ipv6 = config.ipv6;
+async fn enable_rules(profile: Arc<Profile>, ipv6: bool) -> Result<()> {
+    let fwmark = profile.firewall_mark;
+    let table = profile.routing_table;
 
     tokio::task::spawn_blocking(move || {
-        let mut socket = create_handle();
+        let mut socket = create_handle()?;
         add_rule(&mut socket, RtAddrFamily::Inet, fwmark, table)?;
         debug!("enabled ipv4 rules");
 
@@ -199,14 +213,12 @@ async fn enable_rules(config: Arc<Config>) -> Result<()> {
     .await?
 }
 
-async fn disable_rules(config: Arc<Config>) -> Result<()> {
-    let config = config.clone();
-
-    let fwmark = config.firewall_mark;
-    let table = config.routing_table;
+async fn disable_rules(profile: Arc<Profile>) -> Result<()> {
+    let fwmark = profile.firewall_mark;
+    let table = profile.routing_table;
 
     tokio::task::spawn_blocking(move || {
-        let mut socket = create_handle();
+        let mut socket = create_handle()?;
         remove_rule(&mut socket, RtAddrFamily::Inet, fwmark, table)?;
         debug!("disabled ipv4 rules");
 
@@ -219,27 +231,200 @@ async fn disable_rules(config: Arc<Config>) -> Result<()> {
     .await?
 }
 
-fn create_handle() -> NlSocketHandle {
-    NlSocketHandle::connect(NlFamily::Route, None, &[]).unwrap()
+/// Puts back the rules of `profile` and removes those of every other profile, logging whatever
+/// had drifted.
+fn reconcile_rules(profile: Option<Arc<Profile>>, config: Arc<Config>) -> Result<()> {
+    let mut socket = create_handle()?;
+
+    if let Some(profile) = &profile {
+        let (fwmark, table) = (profile.firewall_mark, profile.routing_table);
+
+        let mut families = vec![RtAddrFamily::Inet];
+        if config.ipv6 {
+            families.push(RtAddrFamily::Inet6);
+        }
+
+        for family in families {
+            if !check_rules(&mut socket, family, fwmark, table)? {
+                warn!(
+                    "{:?} rule of profile '{}' is missing, adding it back",
+                    family, profile.name
+                );
+                add_rule(&mut socket, family, fwmark, table)?;
+            }
+        }
+    }
+
+    let wanted = profile.map(|p| (p.firewall_mark, p.routing_table));
+    for other in config.all_profiles() {
+        let (fwmark, table) = (other.firewall_mark, other.routing_table);
+        if wanted == Some((fwmark, table)) {
+            continue;
+        }
+
+        for family in [RtAddrFamily::Inet, RtAddrFamily::Inet6] {
+            if check_rules(&mut socket, family, fwmark, table)? {
+                warn!(
+                    "found {:?} rule of profile '{}' which isn't enabled, removing it",
+                    family, other.name
+                );
+                remove_rule(&mut socket, family, fwmark, table)?;
+            }
+        }
+    }
+
+    Ok(())
 }
 
-pub fn setup(mut rx: Receiver<Msg>, config: Arc<Config>) -> JoinHandle<()> {
-    let config = config.clone();
-    tokio::spawn(async move {
-        while let Ok(m) = rx.recv().await {
-            match m {
-                Msg::Enable => {
-                    if let Err(e) = enable_rules(config.clone()).await {
-                        error!("error on rule enable: {}", e);
-                    }
+fn create_handle() -> Result<NlSocketHandle> {
+    Ok(NlSocketHandle::connect(NlFamily::Route, None, &[])?)
+}
+
+async fn run(command: Command, config: &ConfigRx) -> Result<()> {
+    match command {
+        Command::Enable { old, new } => {
+            // Only remove the old rule if it differs, adding an existing rule is a no-op
+            if let Some(old) = old.filter(|old| {
+                (old.firewall_mark, old.routing_table) != (new.firewall_mark, new.routing_table)
+            }) {
+                disable_rules(old)
+                    .await
+                    .context("failed to disable rules")?;
+            }
+
+            let ipv6 = config.borrow().ipv6;
+            enable_rules(new, ipv6)
+                .await
+                .context("failed to enable rules")
+        }
+        Command::Disable(old) => {
+            // Without a known active profile, clean up after all of them
+            let profiles = match old {
+                Some(profile) => vec![profile],
+                None => config.borrow().all_profiles().cloned().collect(),
+            };
+
+            for profile in profiles {
+                disable_rules(profile)
+                    .await
+                    .context("failed to disable rules")?;
+            }
+            Ok(())
+        }
+        Command::Reconcile(profile) => {
+            let config = config.borrow().clone();
+            tokio::task::spawn_blocking(move || reconcile_rules(profile, config))
+                .await?
+                .context("failed to reconcile rules")
+        }
+    }
+}
+
+/// Adds a rule of the enabled profile back right after someone else deleted it, rather than
+/// waiting for the next reconcile.
+async fn rule_deleted(msg: &Rtmsg, enabled: Option<&Arc<Profile>>, config: &ConfigRx) {
+    let Some(profile) = enabled else {
+        return;
+    };
+    let (fwmark, table) = (profile.firewall_mark, profile.routing_table);
+    if !check_rule(msg, fwmark, table) {
+        return;
+    }
+
+    let family = msg.rtm_family;
+    if family == RtAddrFamily::Inet6 && !config.borrow().ipv6 {
+        return;
+    }
+
+    warn!(
+        "{:?} rule of profile '{}' was deleted, adding it back",
+        family, profile.name
+    );
+    let result = tokio::task::spawn_blocking(move || {
+        let mut socket = create_handle()?;
+        add_rule(&mut socket, family, fwmark, table)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            error!("failed to add rule back: {:#}", e);
+            metrics::error("rule");
+        }
+        Err(e) => {
+            error!("failed to add rule back: {}", e);
+            metrics::error("rule");
+        }
+    }
+}
+
+/// How long to stop listening for deleted rules after failing to receive, doubled every time it
+/// fails again in a row. Commands are still run in the meantime, and reconciling catches up.
+const ERROR_DELAY: Duration = Duration::from_secs(1);
+
+pub fn setup(mut rx: UnboundedReceiver<Request>, config: ConfigRx) -> Result<JoinHandle<()>> {
+    let handle = NlSocketHandle::connect(NlFamily::Route, None, &[])?;
+    handle.add_mcast_membership(&[libc::RTNLGRP_IPV4_RULE, libc::RTNLGRP_IPV6_RULE])?;
+    let mut socket = NlSocket::new(handle)?;
+
+    debug!("got rule notifications");
+
+    Ok(tokio::spawn(async move {
+        // What the last command left enabled. Our own deletions are always for something else,
+        // since this is only updated after a command ran.
+        let mut enabled: Option<Arc<Profile>> = None;
+        let mut buffer = Vec::new();
+        let mut errors = 0;
+        let mut listen_at = Instant::now();
+
+        loop {
+            tokio::select! {
+                request = rx.recv() => {
+                    let Some((command, ack)) = request else {
+                        break;
+                    };
+
+                    let profile = match &command {
+                        Command::Enable { new, .. } => Some(new.clone()),
+                        Command::Disable(_) => None,
+                        Command::Reconcile(profile) => profile.clone(),
+                    };
+                    let _ = ack.send(run(command, &config).await);
+                    enabled = profile;
                 }
-                Msg::Disable => {
-                    if let Err(e) = disable_rules(config.clone()).await {
-                        error!("error on rule enable: {}", e);
+
+                msgs = async {
+                    sleep_until(listen_at).await;
+                    socket.recv::<Rtm, Rtmsg>(&mut buffer).await
+                } => {
+                    let msgs = match msgs {
+                        Ok(msgs) => msgs,
+                        Err(e) => {
+                            let delay = ERROR_DELAY * 2u32.pow(errors.min(6));
+                            error!(
+                                "failed to receive rule notifications, trying again in {}s: {}",
+                                delay.as_secs(),
+                                e
+                            );
+                            metrics::error("rule");
+                            errors += 1;
+                            listen_at = Instant::now() + delay;
+                            continue;
+                        }
+                    };
+                    errors = 0;
+
+                    for msg in msgs {
+                        if msg.nl_type != Rtm::Delrule {
+                            continue;
+                        }
+                        if let Some(payload) = msg.nl_payload.get_payload() {
+                            rule_deleted(payload, enabled.as_ref(), &config).await;
+                        }
                     }
                 }
-                Msg::Quit => break,
             }
         }
-    })
+    }))
 }
@@ -0,0 +1,292 @@
+use serde::Deserialize;
+
+use std::fmt;
+
+/// How a wireless network is protected, ordered from weakest to strongest.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
+#[serde(rename_all = "kebab-case")]
+pub enum Security {
+    /// Open or WEP, which can't be told apart from the association request. Opportunistic
+    /// wireless encryption counts as well, since anyone can still set up the same network.
+    Open,
+    WpaPersonal,
+    WpaEnterprise,
+    Wpa2Personal,
+    Wpa2Enterprise,
+    Wpa3Personal,
+    Wpa3Enterprise,
+}
+
+impl Security {
+    fn downgrade_to_wpa(self) -> Self {
+        match self {
+            Security::Wpa2Personal | Security::Wpa3Personal => Security::WpaPersonal,
+            Security::Wpa2Enterprise | Security::Wpa3Enterprise => Security::WpaEnterprise,
+            s => s,
+        }
+    }
+}
+
+impl fmt::Display for Security {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Security::Open => "open or WEP",
+            Security::WpaPersonal => "wpa-personal",
+            Security::WpaEnterprise => "wpa-enterprise",
+            Security::Wpa2Personal => "wpa2-personal",
+            Security::Wpa2Enterprise => "wpa2-enterprise",
+            Security::Wpa3Personal => "wpa3-personal",
+            Security::Wpa3Enterprise => "wpa3-enterprise",
+        })
+    }
+}
+
+const IE_RSN: u8 = 48;
+const IE_VENDOR: u8 = 221;
+
+const OUI_IEEE: [u8; 3] = [0x00, 0x0f, 0xac];
+/// The pre-standard WPA element is a vendor element of Microsoft.
+const OUI_MICROSOFT: [u8; 3] = [0x00, 0x50, 0xf2];
+
+const CIPHER_WEP40: u8 = 1;
+const CIPHER_TKIP: u8 = 2;
+const CIPHER_CCMP: u8 = 4;
+const CIPHER_WEP104: u8 = 5;
+
+/// Splits information elements into their ids and bodies.
+fn elements(mut ies: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || {
+        let (&id, rest) = ies.split_first()?;
+        let (&len, rest) = rest.split_first()?;
+        let body = rest.get(..len as usize)?;
+        ies = &rest[len as usize..];
+        Some((id, body))
+    })
+}
+
+/// Reads a suite list, returning the suite types with the expected OUI. Lists missing from the
+/// end of an element take the default.
+fn suites(body: &mut &[u8], oui: [u8; 3], default: u8) -> Option<Vec<u8>> {
+    if body.is_empty() {
+        return Some(vec![default]);
+    }
+
+    let count = u16::from_le_bytes(body.get(..2)?.try_into().ok()?) as usize;
+    let list = body.get(2..2 + count * 4)?;
+    *body = &body[2 + count * 4..];
+
+    Some(
+        list.chunks_exact(4)
+            .filter(|s| s[..3] == oui)
+            .map(|s| s[3])
+            .collect(),
+    )
+}
+
+/// Classifies the body of an RSN element or of the vendor WPA element, without the OUI and type.
+fn classify(mut body: &[u8], oui: [u8; 3], rsn: bool) -> Option<Security> {
+    // Skip the version and the group cipher, the pairwise ciphers are what protect our traffic
+    body = body.get(2..)?;
+    body = body.get(4..).unwrap_or_default();
+
+    let default_cipher = if rsn { CIPHER_CCMP } else { CIPHER_TKIP };
+    let ciphers = suites(&mut body, oui, default_cipher)?;
+    let akms = suites(&mut body, oui, 1)?;
+
+    let security = akms
+        .iter()
+        .filter_map(|akm| match (rsn, akm) {
+            (true, 1 | 3 | 5) => Some(Security::Wpa2Enterprise),
+            (true, 2 | 4 | 6) => Some(Security::Wpa2Personal),
+            (true, 8 | 9 | 24 | 25) => Some(Security::Wpa3Personal),
+            (true, 11..=13) => Some(Security::Wpa3Enterprise),
+            (true, 18) => Some(Security::Open),
+            (false, 1) => Some(Security::WpaEnterprise),
+            (false, 2) => Some(Security::WpaPersonal),
+            _ => None,
+        })
+        .min()?;
+
+    if ciphers
+        .iter()
+        .any(|c| matches!(*c, CIPHER_WEP40 | CIPHER_WEP104))
+    {
+        Some(Security::Open)
+    } else if ciphers.contains(&CIPHER_TKIP) {
+        Some(security.downgrade_to_wpa())
+    } else {
+        Some(security)
+    }
+}
+
+/// Works out the security of a network from information elements, either those of our
+/// association request or those advertised by the access point. When several options are
+/// advertised the weakest one is picked, since that is what an impostor would offer.
+pub fn from_ies(ies: &[u8]) -> Security {
+    elements(ies)
+        .filter_map(|(id, body)| match id {
+            IE_RSN => classify(body, OUI_IEEE, true),
+            IE_VENDOR if body.starts_with(&OUI_MICROSOFT) && body.get(3) == Some(&1) => {
+                classify(&body[4..], OUI_MICROSOFT, false)
+            }
+            _ => None,
+        })
+        .min()
+        .unwrap_or(Security::Open)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AKM_8021X: u8 = 1;
+    const AKM_PSK: u8 = 2;
+    const AKM_SAE: u8 = 8;
+    const AKM_SUITE_B_192: u8 = 12;
+    const AKM_OWE: u8 = 18;
+
+    fn element(id: u8, body: &[u8]) -> Vec<u8> {
+        let mut element = vec![id, body.len() as u8];
+        element.extend_from_slice(body);
+        element
+    }
+
+    fn suite_list(oui: [u8; 3], suites: &[u8]) -> Vec<u8> {
+        let mut list = (suites.len() as u16).to_le_bytes().to_vec();
+        for suite in suites {
+            list.extend_from_slice(&oui);
+            list.push(*suite);
+        }
+        list
+    }
+
+    fn rsn(ciphers: &[u8], akms: &[u8]) -> Vec<u8> {
+        let mut body = vec![1, 0];
+        body.extend_from_slice(&OUI_IEEE);
+        body.push(CIPHER_CCMP);
+        body.extend(suite_list(OUI_IEEE, ciphers));
+        body.extend(suite_list(OUI_IEEE, akms));
+        // The capabilities
+        body.extend([0, 0]);
+        element(IE_RSN, &body)
+    }
+
+    fn wpa(ciphers: &[u8], akms: &[u8]) -> Vec<u8> {
+        let mut body = OUI_MICROSOFT.to_vec();
+        body.extend([1, 1, 0]);
+        body.extend_from_slice(&OUI_MICROSOFT);
+        body.push(CIPHER_TKIP);
+        body.extend(suite_list(OUI_MICROSOFT, ciphers));
+        body.extend(suite_list(OUI_MICROSOFT, akms));
+        element(IE_VENDOR, &body)
+    }
+
+    #[test]
+    fn open_and_wep() {
+        assert_eq!(from_ies(&[]), Security::Open);
+        assert_eq!(from_ies(&element(0, b"Cafe")), Security::Open);
+
+        assert_eq!(from_ies(&rsn(&[CIPHER_WEP40], &[AKM_PSK])), Security::Open);
+        assert_eq!(from_ies(&rsn(&[CIPHER_WEP104], &[AKM_PSK])), Security::Open);
+        assert_eq!(from_ies(&wpa(&[CIPHER_WEP40], &[AKM_PSK])), Security::Open);
+    }
+
+    #[test]
+    fn wpa_with_tkip() {
+        let ies = wpa(&[CIPHER_TKIP], &[AKM_PSK]);
+        assert_eq!(from_ies(&ies), Security::WpaPersonal);
+
+        let ies = wpa(&[CIPHER_TKIP], &[AKM_8021X]);
+        assert_eq!(from_ies(&ies), Security::WpaEnterprise);
+
+        // TKIP in an RSN element is no better than WPA
+        let ies = rsn(&[CIPHER_TKIP], &[AKM_PSK]);
+        assert_eq!(from_ies(&ies), Security::WpaPersonal);
+    }
+
+    #[test]
+    fn wpa2_personal() {
+        let ies = [element(0, b"Home"), rsn(&[CIPHER_CCMP], &[AKM_PSK])].concat();
+        assert_eq!(from_ies(&ies), Security::Wpa2Personal);
+    }
+
+    #[test]
+    fn mixed_wpa_and_wpa2_is_the_weaker() {
+        let ies = [
+            rsn(&[CIPHER_CCMP], &[AKM_PSK]),
+            wpa(&[CIPHER_TKIP], &[AKM_PSK]),
+        ]
+        .concat();
+        assert_eq!(from_ies(&ies), Security::WpaPersonal);
+
+        let ies = rsn(&[CIPHER_CCMP, CIPHER_TKIP], &[AKM_PSK]);
+        assert_eq!(from_ies(&ies), Security::WpaPersonal);
+    }
+
+    #[test]
+    fn sae() {
+        let ies = rsn(&[CIPHER_CCMP], &[AKM_SAE]);
+        assert_eq!(from_ies(&ies), Security::Wpa3Personal);
+
+        // Transition mode still lets older clients in with a PSK
+        let ies = rsn(&[CIPHER_CCMP], &[AKM_PSK, AKM_SAE]);
+        assert_eq!(from_ies(&ies), Security::Wpa2Personal);
+    }
+
+    #[test]
+    fn enterprise() {
+        let ies = rsn(&[CIPHER_CCMP], &[AKM_8021X]);
+        assert_eq!(from_ies(&ies), Security::Wpa2Enterprise);
+
+        let ies = rsn(&[CIPHER_CCMP], &[AKM_SUITE_B_192]);
+        assert_eq!(from_ies(&ies), Security::Wpa3Enterprise);
+
+        // Without the suite lists the defaults are CCMP and 802.1X
+        let ies = element(IE_RSN, &[1, 0, 0x00, 0x0f, 0xac, CIPHER_CCMP]);
+        assert_eq!(from_ies(&ies), Security::Wpa2Enterprise);
+    }
+
+    #[test]
+    fn owe() {
+        let ies = rsn(&[CIPHER_CCMP], &[AKM_OWE]);
+        assert_eq!(from_ies(&ies), Security::Open);
+    }
+
+    #[test]
+    fn malformed_elements() {
+        // The AKM list claims more suites than there are
+        let mut ies = rsn(&[CIPHER_CCMP], &[AKM_PSK]);
+        ies[14] = 2;
+        assert_eq!(from_ies(&ies), Security::Open);
+
+        // Unknown AKMs and suites of another vendor don't count
+        assert_eq!(from_ies(&rsn(&[CIPHER_CCMP], &[7])), Security::Open);
+        let mut ies = rsn(&[CIPHER_CCMP], &[AKM_PSK]);
+        ies[16] = 0x50;
+        assert_eq!(from_ies(&ies), Security::Open);
+
+        assert_eq!(from_ies(&element(IE_RSN, &[1])), Security::Open);
+        assert_eq!(
+            from_ies(&element(IE_VENDOR, &OUI_MICROSOFT)),
+            Security::Open
+        );
+        assert_eq!(from_ies(&[IE_RSN, 200, 1, 0]), Security::Open);
+
+        // Nothing that is cut off or garbled makes it panic
+        let ies = [
+            rsn(&[CIPHER_CCMP], &[AKM_PSK, AKM_SAE]),
+            wpa(&[CIPHER_TKIP], &[AKM_PSK]),
+        ]
+        .concat();
+        for len in 0..ies.len() {
+            from_ies(&ies[..len]);
+        }
+        for i in 0..ies.len() {
+            for byte in [0x00, 0x01, 0x7f, 0xff] {
+                let mut garbled = ies.clone();
+                garbled[i] = byte;
+                from_ies(&garbled);
+            }
+        }
+    }
+}
@@ -0,0 +1,1061 @@
+use anyhow::{anyhow, Result};
+
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, timeout, timeout_at, Duration, Instant};
+
+use log::*;
+
+use std::fmt;
+use std::sync::Arc;
+
+use super::hooks::{Hook, Hooks};
+use super::{metrics, ConfigRx, Profile};
+
+/// What the links ask for, see [`super::links::Links`].
+#[derive(Clone, Debug)]
+pub enum Target {
+    Disconnected,
+    Trusted,
+    Untrusted(Arc<Profile>),
+}
+
+#[derive(Clone, Debug)]
+pub enum State {
+    Disconnected,
+    Trusted,
+    Untrusted(Arc<Profile>),
+    /// The VPN was turned on by hand, whatever the network.
+    Forced(Arc<Profile>),
+    /// Commands were sent to reach `to` and haven't all finished yet.
+    Transitioning {
+        to: Box<State>,
+    },
+    /// The VPN was turned off by hand, whatever the network, until the given time if any.
+    Paused(Option<Instant>),
+    /// Reaching `to` failed and whatever was applied was rolled back. It is tried again after a
+    /// while, or with the next event.
+    Failed {
+        to: Box<State>,
+        reason: String,
+    },
+    /// The tunnel of the given state is up but got no handshake in time.
+    Unhealthy(Box<State>),
+}
+
+/// Every [`State::kind`].
+pub const KINDS: [&str; 8] = [
+    "disconnected",
+    "trusted",
+    "untrusted",
+    "forced",
+    "transitioning",
+    "paused",
+    "failed",
+    "unhealthy",
+];
+
+impl State {
+    /// The name of the state without the details, for the metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            State::Disconnected => "disconnected",
+            State::Trusted => "trusted",
+            State::Untrusted(_) => "untrusted",
+            State::Forced(_) => "forced",
+            State::Transitioning { .. } => "transitioning",
+            State::Paused(_) => "paused",
+            State::Failed { .. } => "failed",
+            State::Unhealthy(_) => "unhealthy",
+        }
+    }
+
+    /// The profile of the tunnel, if it is up.
+    pub fn profile(&self) -> Option<&Arc<Profile>> {
+        match self {
+            State::Untrusted(profile) | State::Forced(profile) => Some(profile),
+            State::Unhealthy(state) => state.profile(),
+            _ => None,
+        }
+    }
+
+    /// The state without whether the tunnel is healthy.
+    fn tunnel(&self) -> &State {
+        match self {
+            State::Unhealthy(state) => state,
+            state => state,
+        }
+    }
+
+    fn same(&self, other: &State) -> bool {
+        match (self, other) {
+            (State::Untrusted(a), State::Untrusted(b)) | (State::Forced(a), State::Forced(b)) => {
+                a == b
+            }
+            (State::Paused(a), State::Paused(b)) => a == b,
+            (State::Unhealthy(a), State::Unhealthy(b)) => a.same(b),
+            (State::Transitioning { .. } | State::Failed { .. }, _)
+            | (_, State::Transitioning { .. } | State::Failed { .. }) => false,
+            (a, b) => std::mem::discriminant(a) == std::mem::discriminant(b),
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            State::Disconnected => f.write_str("disconnected"),
+            State::Trusted => f.write_str("trusted"),
+            State::Untrusted(profile) => write!(f, "untrusted, using profile '{}'", profile.name),
+            State::Transitioning { to } => write!(f, "transitioning to {}", to),
+            State::Forced(profile) => write!(f, "forced on, using profile '{}'", profile.name),
+            State::Paused(None) => f.write_str("paused"),
+            State::Paused(Some(until)) => {
+                let left = until.saturating_duration_since(Instant::now()).as_secs();
+                write!(f, "paused for another {}m{}s", left / 60, left % 60)
+            }
+            State::Failed { to, reason } => write!(f, "failed to get {}: {}", to, reason),
+            State::Unhealthy(state) => write!(f, "{}, but the tunnel gets no handshake", state),
+        }
+    }
+}
+
+pub enum Event {
+    Network(Target),
+    /// Turns the VPN off until the given time, or until [`Event::Resume`].
+    Pause(Option<Instant>),
+    /// Turns the VPN on with the given profile until [`Event::Resume`].
+    Force(Arc<Profile>),
+    /// Goes back to following the network.
+    Resume,
+    /// Every command of the last transition finished.
+    Done,
+    /// A command of the last transition failed, and the ones before it were undone.
+    Failed(String),
+    /// Tries again after a failed transition.
+    Retry,
+    /// Tear everything down before quitting.
+    Shutdown,
+    /// Whether the tunnel on the given WireGuard interface gets handshakes.
+    Health {
+        interface: String,
+        healthy: bool,
+    },
+}
+
+impl Event {
+    /// What caused a transition, for the metrics.
+    fn reason(&self) -> &'static str {
+        match self {
+            Event::Network(_) => "network",
+            Event::Pause(_) => "pause",
+            Event::Force(_) => "force",
+            Event::Resume => "resume",
+            Event::Done => "done",
+            Event::Failed(_) => "failed",
+            Event::Retry => "retry",
+            Event::Shutdown => "shutdown",
+            Event::Health { .. } => "health",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsystem {
+    Firewall,
+    Wireguard,
+    Rule,
+    Networkd,
+}
+
+impl Subsystem {
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::Firewall => "firewall",
+            Subsystem::Wireguard => "wireguard",
+            Subsystem::Rule => "rule",
+            Subsystem::Networkd => "networkd",
+        }
+    }
+}
+
+/// What a subsystem is told to do. Subsystems don't keep track of the active profile, the
+/// commands carry the one that was set up before so that only what differs has to be changed.
+#[derive(Clone, Debug)]
+pub enum Command {
+    Enable {
+        old: Option<Arc<Profile>>,
+        new: Arc<Profile>,
+    },
+    /// `None` if it isn't known what was set up, in which case every profile is cleaned up.
+    Disable(Option<Arc<Profile>>),
+    /// Checks that what was set up is still in place, e.g. after another tool changed it, and
+    /// repairs it if not. `None` means no profile is enabled.
+    Reconcile(Option<Arc<Profile>>),
+}
+
+impl Command {
+    /// The command that puts things back the way they were before this one, if that is known.
+    fn undo(&self) -> Option<Command> {
+        match self {
+            Command::Enable {
+                old: Some(old),
+                new,
+            } => Some(Command::Enable {
+                old: Some(new.clone()),
+                new: old.clone(),
+            }),
+            Command::Enable { old: None, new } => Some(Command::Disable(Some(new.clone()))),
+            Command::Disable(Some(old)) => Some(Command::Enable {
+                old: None,
+                new: old.clone(),
+            }),
+            Command::Disable(None) | Command::Reconcile(_) => None,
+        }
+    }
+}
+
+/// How long to wait before trying again after the first failure, doubled for every one after it.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Owns the transitions between states. This is kept apart from running the commands so that it
+/// can be driven without any sockets.
+pub struct Machine {
+    state: State,
+    target: Target,
+    overridden: Option<State>,
+    shutdown: bool,
+    /// Failures since the last transition that went through.
+    failures: u32,
+    /// When to send [`Event::Retry`] after a failure.
+    retry_at: Option<Instant>,
+    /// The profile the subsystems were last told to enable, `Some(None)` if they were last told to
+    /// disable and `None` if it isn't known, such as before they were told anything.
+    applied: Option<Option<Arc<Profile>>>,
+    /// What was applied before the current transition, restored if it fails.
+    rollback: Option<Option<Arc<Profile>>>,
+}
+
+impl Machine {
+    pub fn new() -> Self {
+        Machine {
+            state: State::Disconnected,
+            target: Target::Disconnected,
+            overridden: None,
+            shutdown: false,
+            failures: 0,
+            retry_at: None,
+            applied: None,
+            rollback: None,
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// When to try again after a failed transition, at which point [`Event::Retry`] should be sent.
+    pub fn retry_at(&self) -> Option<Instant> {
+        self.retry_at
+    }
+
+    /// When a timed pause runs out, at which point [`Event::Resume`] should be sent.
+    pub fn resume_at(&self) -> Option<Instant> {
+        match self.overridden {
+            Some(State::Paused(until)) => until,
+            _ => None,
+        }
+    }
+
+    fn wanted(&self) -> State {
+        if self.shutdown {
+            return State::Disconnected;
+        }
+        if let Some(state) = &self.overridden {
+            return state.clone();
+        }
+
+        match &self.target {
+            Target::Disconnected => State::Disconnected,
+            Target::Trusted => State::Trusted,
+            Target::Untrusted(profile) => State::Untrusted(profile.clone()),
+        }
+    }
+
+    /// The commands that check for drift from what was applied, nothing while in a transition or
+    /// before anything was applied.
+    pub fn reconcile(&self) -> Vec<(Subsystem, Command)> {
+        if matches!(self.state, State::Transitioning { .. }) {
+            return Vec::new();
+        }
+        let Some(applied) = &self.applied else {
+            return Vec::new();
+        };
+
+        [Subsystem::Firewall, Subsystem::Rule, Subsystem::Networkd]
+            .into_iter()
+            .map(|s| (s, Command::Reconcile(applied.clone())))
+            .collect()
+    }
+
+    /// Applies `event`, returning the commands to run in order. [`Event::Done`] or
+    /// [`Event::Failed`] has to be sent once they finished, until then any other event is only
+    /// recorded.
+    pub fn handle(&mut self, event: Event) -> Vec<(Subsystem, Command)> {
+        match event {
+            Event::Network(target) => self.target = target,
+            Event::Pause(until) => self.overridden = Some(State::Paused(until)),
+            Event::Force(profile) => self.overridden = Some(State::Forced(profile)),
+            Event::Resume => self.overridden = None,
+            Event::Shutdown => self.shutdown = true,
+            Event::Retry => {}
+            Event::Done => {
+                if let State::Transitioning { to } = &self.state {
+                    self.state = (**to).clone();
+                    self.failures = 0;
+                }
+            }
+            Event::Failed(reason) => {
+                if let State::Transitioning { to } = &self.state {
+                    self.state = State::Failed {
+                        to: to.clone(),
+                        reason,
+                    };
+                    // A tunnel that failed to come up keeps the kill switch, so it is no longer
+                    // known what is set up and turning it off has to clean up everything
+                    self.applied = match self.rollback.take() {
+                        Some(None) => None,
+                        rollback => rollback,
+                    };
+                }
+
+                // Trying again right away would most likely fail the same way
+                let delay = RETRY_DELAY * 2u32.pow(self.failures.min(6));
+                self.failures += 1;
+                self.retry_at = Some(Instant::now() + delay);
+                return Vec::new();
+            }
+            Event::Health { interface, healthy } => {
+                // Reports can be late, only the tunnel that is up counts
+                let up = self.state.profile();
+                if up.is_some_and(|p| p.wireguard_interface == interface) {
+                    let tunnel = self.state.tunnel().clone();
+                    self.state = match healthy {
+                        true => tunnel,
+                        false => State::Unhealthy(Box::new(tunnel)),
+                    };
+                }
+
+                // The tunnel stays as it is, the listen port is changed by wireguard itself
+                return Vec::new();
+            }
+        }
+
+        if matches!(self.state, State::Transitioning { .. }) {
+            return Vec::new();
+        }
+        // Whatever comes next is tried now
+        self.retry_at = None;
+
+        let wanted = self.wanted();
+        if wanted.same(self.state.tunnel()) && self.applied.is_some() {
+            return Vec::new();
+        }
+
+        let old = self.applied.clone().flatten();
+        let commands = match (wanted.profile(), &self.applied) {
+            (Some(new), Some(Some(old))) if new == old => Vec::new(),
+            // Nothing may leak while the tunnel comes up, and the tunnel has to be up before
+            // traffic is routed into it
+            (Some(new), _) => [
+                Subsystem::Firewall,
+                Subsystem::Wireguard,
+                Subsystem::Rule,
+                Subsystem::Networkd,
+            ]
+            .into_iter()
+            .map(|s| {
+                let command = Command::Enable {
+                    old: old.clone(),
+                    new: new.clone(),
+                };
+                (s, command)
+            })
+            .collect(),
+            (None, Some(None)) => Vec::new(),
+            (None, _) => [
+                Subsystem::Networkd,
+                Subsystem::Rule,
+                Subsystem::Wireguard,
+                Subsystem::Firewall,
+            ]
+            .into_iter()
+            .map(|s| (s, Command::Disable(old.clone())))
+            .collect(),
+        };
+
+        self.rollback = self.applied.replace(wanted.profile().cloned());
+        self.state = if commands.is_empty() {
+            wanted
+        } else {
+            State::Transitioning {
+                to: Box::new(wanted),
+            }
+        };
+
+        commands
+    }
+}
+
+/// How tearing down went for every subsystem that had something to undo, `Err` if it may have
+/// left something behind.
+pub type Teardown = Vec<(Subsystem, Result<(), String>)>;
+
+/// A command along with the sender to report the outcome on once it was carried out.
+pub type Request = (Command, oneshot::Sender<Result<()>>);
+
+pub struct Subsystems {
+    pub firewall: UnboundedSender<Request>,
+    pub wireguard: UnboundedSender<Request>,
+    pub rule: UnboundedSender<Request>,
+    pub networkd: UnboundedSender<Request>,
+}
+
+impl Subsystems {
+    async fn run(&self, subsystem: Subsystem, command: Command) -> Result<()> {
+        let tx = match subsystem {
+            Subsystem::Firewall => &self.firewall,
+            Subsystem::Wireguard => &self.wireguard,
+            Subsystem::Rule => &self.rule,
+            Subsystem::Networkd => &self.networkd,
+        };
+
+        let (ack, done) = oneshot::channel();
+        tx.send((command, ack))
+            .map_err(|_| anyhow!("{:?} is gone", subsystem))?;
+        let result = done.await.map_err(|_| anyhow!("{:?} is gone", subsystem))?;
+
+        if result.is_err() {
+            metrics::error(subsystem.name());
+        }
+        result
+    }
+
+    /// Runs `commands` in order. If one fails, it and every command before it are undone in
+    /// reverse order, since a failed command may have been applied halfway. The kill switch is
+    /// the exception, it stays in place when the tunnel fails to come up.
+    async fn transaction(&self, commands: Vec<(Subsystem, Command)>) -> Result<(), String> {
+        let mut ran = Vec::new();
+
+        for (subsystem, command) in commands {
+            let result = self.run(subsystem, command.clone()).await;
+            ran.push((subsystem, command));
+
+            if let Err(e) = result {
+                warn!("{:?} failed, rolling back", subsystem);
+                for (subsystem, command) in ran.into_iter().rev() {
+                    let Some(undo) = command.undo() else {
+                        continue;
+                    };
+                    // Taking it down would let traffic out on the network that needs the tunnel
+                    if subsystem == Subsystem::Firewall && matches!(undo, Command::Disable(_)) {
+                        continue;
+                    }
+                    if let Err(e) = self.run(subsystem, undo).await {
+                        error!("failed to roll back {:?}: {:#}", subsystem, e);
+                    }
+                }
+
+                return Err(format!("{:?} failed: {:#}", subsystem, e));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs every command of a shutdown by `deadline`. Unlike a transaction nothing is rolled
+    /// back, whatever can be cleaned up should be. Each command gets an equal share of the time
+    /// that is left, so that one that is stuck doesn't keep the others from running.
+    async fn teardown(&self, commands: Vec<(Subsystem, Command)>, deadline: Instant) -> Teardown {
+        let mut outcomes = Vec::new();
+        let count = commands.len() as u32;
+
+        for (i, (subsystem, command)) in (0..count).zip(commands) {
+            let share = deadline.saturating_duration_since(Instant::now()) / (count - i);
+            let result = match timeout(share, self.run(subsystem, command)).await {
+                Ok(result) => result.map_err(|e| format!("{:#}", e)),
+                Err(_) => Err(String::from("ran out of time")),
+            };
+            outcomes.push((subsystem, result));
+        }
+
+        outcomes
+    }
+}
+
+/// The hooks that go around `commands`, which all turn the tunnel on or all turn it off, along
+/// with the profile they are about. Cleaning up without knowing what was set up runs none.
+fn hooks_for(commands: &[(Subsystem, Command)]) -> Option<(Hook, Hook, Arc<Profile>)> {
+    match &commands.first()?.1 {
+        Command::Enable { new, .. } => Some((Hook::PreEnable, Hook::PostEnable, new.clone())),
+        Command::Disable(old) => Some((Hook::PreDisable, Hook::PostDisable, old.clone()?)),
+        Command::Reconcile(_) => None,
+    }
+}
+
+/// Runs `commands` in between their hooks, returning the event that ends the transition. A pre
+/// hook can only stop it before anything was applied.
+async fn transition(
+    subsystems: &Subsystems,
+    hooks: &Hooks,
+    commands: Vec<(Subsystem, Command)>,
+    reason: &'static str,
+) -> Event {
+    let around = hooks_for(&commands);
+
+    if let Some((pre, _, profile)) = &around {
+        if let Err(e) = hooks.run(*pre, Some(profile), reason).await {
+            return Event::Failed(format!("{:#}", e));
+        }
+    }
+
+    if let Err(reason) = subsystems.transaction(commands).await {
+        return Event::Failed(reason);
+    }
+
+    if let Some((_, post, profile)) = &around {
+        // Only a pre hook can veto, so this never fails
+        let _ = hooks.run(*post, Some(profile), reason).await;
+    }
+    Event::Done
+}
+
+/// How much of the time for a shutdown its pre hook may take, as a fraction of it. The rest is
+/// kept for the subsystems, so a slow hook can't leave the rules and DNS settings in place.
+const PRE_HOOK_SHARE: u32 = 4;
+
+/// Like [`transition`] for the commands of a shutdown, where the hooks can't stop anything and
+/// everything has to be done by `deadline`.
+async fn teardown(
+    subsystems: &Subsystems,
+    hooks: &Hooks,
+    commands: Vec<(Subsystem, Command)>,
+    deadline: Instant,
+) -> Teardown {
+    let around = hooks_for(&commands);
+    let reason = Event::Shutdown.reason();
+
+    if let Some((pre, _, profile)) = &around {
+        let limit = deadline.saturating_duration_since(Instant::now()) / PRE_HOOK_SHARE;
+        match timeout(limit, hooks.run(*pre, Some(profile), reason)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("{:#}, shutting down anyway", e),
+            Err(_) => error!(
+                "gave up on the {:?} hook to leave time for shutting down",
+                pre
+            ),
+        }
+    }
+
+    let outcomes = subsystems.teardown(commands, deadline).await;
+
+    if let Some((_, post, profile)) = &around {
+        let clean = outcomes.iter().all(|(_, result)| result.is_ok());
+        let hook = timeout_at(deadline, hooks.run(*post, Some(profile), reason));
+        if clean && hook.await.is_err() {
+            error!("ran out of time to shut down running hooks");
+        }
+    }
+    outcomes
+}
+
+fn reconcile_interval(config: &ConfigRx) -> Duration {
+    Duration::from_secs(config.borrow().reconcile_interval)
+}
+
+pub type StateRx = watch::Receiver<State>;
+
+/// Runs the state machine, the subsystems stop once it does after [`Event::Shutdown`], which has
+/// to be done within `shutdown_timeout` and ends with how it went. In between
+/// events, what was applied is checked for drift every `reconcile_interval`. Every state it goes
+/// through is published on `status`, and `hooks` are run around turning the tunnel on and off and
+/// when its health changes.
+pub fn setup(
+    mut events: UnboundedReceiver<Event>,
+    subsystems: Subsystems,
+    hooks: Hooks,
+    config: ConfigRx,
+    status: watch::Sender<State>,
+) -> JoinHandle<Teardown> {
+    tokio::spawn(async move {
+        let mut machine = Machine::new();
+        let mut report = Teardown::new();
+        let mut next_reconcile = Instant::now() + reconcile_interval(&config);
+
+        loop {
+            let resume_at = machine.resume_at();
+            let retry_at = machine.retry_at();
+            let event = tokio::select! {
+                event = events.recv() => event,
+
+                _ = sleep_until(next_reconcile), if !reconcile_interval(&config).is_zero() => {
+                    for (subsystem, command) in machine.reconcile() {
+                        if let Err(e) = subsystems.run(subsystem, command).await {
+                            error!("failed to reconcile {:?}: {:#}", subsystem, e);
+                        }
+                    }
+                    next_reconcile = Instant::now() + reconcile_interval(&config);
+                    continue;
+                }
+
+                _ = sleep_until(resume_at.unwrap_or(next_reconcile)), if resume_at.is_some() => {
+                    info!("pause ran out");
+                    Some(Event::Resume)
+                }
+
+                _ = sleep_until(retry_at.unwrap_or(next_reconcile)), if retry_at.is_some() => {
+                    info!("trying the failed transition again");
+                    Some(Event::Retry)
+                }
+            };
+            let Some(event) = event else {
+                break;
+            };
+
+            let shutdown = matches!(event, Event::Shutdown);
+            let health = matches!(event, Event::Health { .. });
+            let deadline = Instant::now() + Duration::from_secs(config.borrow().shutdown_timeout);
+            let reason = event.reason();
+            let before = machine.state().clone();
+
+            let mut commands = machine.handle(event);
+            while !commands.is_empty() {
+                debug!("state is now {}", machine.state());
+                metrics::enter(machine.state());
+                status.send_replace(machine.state().clone());
+                let event = if shutdown {
+                    let outcomes = teardown(&subsystems, &hooks, commands, deadline).await;
+                    let failed: Vec<_> = outcomes
+                        .iter()
+                        .filter_map(|(s, r)| Some(format!("{:?} failed: {}", s, r.as_ref().err()?)))
+                        .collect();
+                    report.extend(outcomes);
+
+                    if failed.is_empty() {
+                        Event::Done
+                    } else {
+                        Event::Failed(failed.join(", "))
+                    }
+                } else {
+                    transition(&subsystems, &hooks, commands, reason).await
+                };
+                commands = machine.handle(event);
+            }
+
+            match machine.state() {
+                State::Failed { .. } => error!("state is now {}", machine.state()),
+                state if !before.same(state) => info!("state is now {}", state),
+                _ => {}
+            }
+            if !before.same(machine.state()) {
+                metrics::transition(machine.state(), reason);
+            }
+            metrics::enter(machine.state());
+            status.send_replace(machine.state().clone());
+
+            // Turning off a tunnel that was unhealthy doesn't count as it recovering
+            if health && !before.same(machine.state()) {
+                let hook = match machine.state() {
+                    State::Unhealthy(_) => Hook::Unhealthy,
+                    _ => Hook::Healthy,
+                };
+                let profile = machine.state().profile().cloned();
+                let _ = hooks.run(hook, profile.as_deref(), reason).await;
+            }
+
+            if shutdown {
+                break;
+            }
+        }
+
+        report
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    use std::sync::Mutex;
+
+    const CONFIG: &str = r#"
+wireguard_interface = "wg0"
+wlan_interfaces = []
+known_networks = []
+firewall_mark = 1
+routing_table = 2
+ipv6 = false
+
+[[profile]]
+name = "work"
+wireguard_interface = "wg1"
+firewall_mark = 3
+routing_table = 4
+"#;
+
+    /// Loads `CONFIG` with `extra` added to it.
+    fn load(extra: &str) -> config::Config {
+        let path = std::env::temp_dir().join(format!(
+            "autovpn-state-{}-{:?}.toml",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        std::fs::write(&path, format!("{}{}", CONFIG, extra)).unwrap();
+        let config = config::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        config
+    }
+
+    /// The default profile and the one named `work`, new ones every time like after a reload.
+    fn profiles() -> (Arc<Profile>, Arc<Profile>) {
+        let config = load("");
+        let work = config.profile_named("work").unwrap().clone();
+        (config.default_profile.clone(), work)
+    }
+
+    /// Subsystems that only write down what they were told, failing to enable `failing`.
+    struct Fake {
+        subsystems: Subsystems,
+        log: Arc<Mutex<Vec<String>>>,
+        failing: Arc<Mutex<Option<Subsystem>>>,
+    }
+
+    impl Fake {
+        fn new() -> Self {
+            let log = Arc::new(Mutex::new(Vec::new()));
+            let failing = Arc::new(Mutex::new(None));
+
+            let spawn = |subsystem: Subsystem| {
+                let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Request>();
+                let (log, failing) = (log.clone(), failing.clone());
+                tokio::spawn(async move {
+                    while let Some((command, ack)) = rx.recv().await {
+                        let what = match &command {
+                            Command::Enable { new, .. } => format!("enable {}", new.name),
+                            Command::Disable(Some(old)) => format!("disable {}", old.name),
+                            Command::Disable(None) => String::from("disable all"),
+                            Command::Reconcile(_) => String::from("reconcile"),
+                        };
+                        log.lock()
+                            .unwrap()
+                            .push(format!("{} {}", subsystem.name(), what));
+
+                        let fail = *failing.lock().unwrap() == Some(subsystem)
+                            && matches!(command, Command::Enable { .. });
+                        let _ = ack.send(match fail {
+                            true => Err(anyhow!("broken")),
+                            false => Ok(()),
+                        });
+                    }
+                });
+                tx
+            };
+
+            let subsystems = Subsystems {
+                firewall: spawn(Subsystem::Firewall),
+                wireguard: spawn(Subsystem::Wireguard),
+                rule: spawn(Subsystem::Rule),
+                networkd: spawn(Subsystem::Networkd),
+            };
+            Fake {
+                subsystems,
+                log,
+                failing,
+            }
+        }
+
+        /// Hands `event` to `machine` and carries out every transition that follows, returning
+        /// what the subsystems were told.
+        async fn drive(&self, machine: &mut Machine, event: Event) -> Vec<String> {
+            let mut commands = machine.handle(event);
+            while !commands.is_empty() {
+                let event = match self.subsystems.transaction(commands).await {
+                    Ok(()) => Event::Done,
+                    Err(reason) => Event::Failed(reason),
+                };
+                commands = machine.handle(event);
+            }
+
+            std::mem::take(&mut *self.log.lock().unwrap())
+        }
+    }
+
+    fn enable(name: &str) -> Vec<String> {
+        ["firewall", "wireguard", "rule", "networkd"]
+            .iter()
+            .map(|s| format!("{} enable {}", s, name))
+            .collect()
+    }
+
+    fn disable(name: &str) -> Vec<String> {
+        ["networkd", "rule", "wireguard", "firewall"]
+            .iter()
+            .map(|s| format!("{} disable {}", s, name))
+            .collect()
+    }
+
+    fn health(interface: &str, healthy: bool) -> Event {
+        Event::Health {
+            interface: interface.to_string(),
+            healthy,
+        }
+    }
+
+    #[tokio::test]
+    async fn enabling_twice_does_nothing() {
+        let fake = Fake::new();
+        let mut machine = Machine::new();
+        let (default, _) = profiles();
+
+        let event = Event::Network(Target::Untrusted(default.clone()));
+        assert_eq!(fake.drive(&mut machine, event).await, enable("default"));
+        assert!(matches!(machine.state(), State::Untrusted(_)));
+
+        let event = Event::Network(Target::Untrusted(default));
+        assert!(fake.drive(&mut machine, event).await.is_empty());
+
+        // Reloading an unchanged config makes new profiles that are still the same
+        let (reloaded, _) = profiles();
+        let event = Event::Network(Target::Untrusted(reloaded));
+        assert!(fake.drive(&mut machine, event).await.is_empty());
+        assert!(matches!(machine.state(), State::Untrusted(_)));
+    }
+
+    #[tokio::test]
+    async fn events_during_a_transition_are_applied_after_it() {
+        let fake = Fake::new();
+        let mut machine = Machine::new();
+        let (default, _) = profiles();
+
+        let commands = machine.handle(Event::Network(Target::Untrusted(default)));
+        assert_eq!(commands.len(), 4);
+        assert!(matches!(machine.state(), State::Transitioning { .. }));
+
+        assert!(machine.handle(Event::Network(Target::Trusted)).is_empty());
+        assert!(machine.handle(health("wg0", false)).is_empty());
+        assert!(machine.reconcile().is_empty());
+        assert!(matches!(machine.state(), State::Transitioning { .. }));
+
+        // The tunnel that was being brought up is taken down again
+        let log = fake.drive(&mut machine, Event::Done).await;
+        assert_eq!(log, disable("default"));
+        assert!(matches!(machine.state(), State::Trusted));
+    }
+
+    #[tokio::test]
+    async fn failing_rolls_back_but_keeps_the_kill_switch() {
+        let fake = Fake::new();
+        let mut machine = Machine::new();
+        let (default, _) = profiles();
+
+        *fake.failing.lock().unwrap() = Some(Subsystem::Rule);
+        let event = Event::Network(Target::Untrusted(default));
+        let log = fake.drive(&mut machine, event).await;
+        assert_eq!(
+            log,
+            [
+                "firewall enable default",
+                "wireguard enable default",
+                "rule enable default",
+                "rule disable default",
+                "wireguard disable default",
+            ]
+        );
+        assert!(matches!(machine.state(), State::Failed { .. }));
+        assert!(machine.reconcile().is_empty());
+
+        // Tried again later rather than waiting for the next event
+        let retry_at = machine.retry_at().unwrap();
+        assert!(retry_at > Instant::now() + Duration::from_secs(4));
+
+        *fake.failing.lock().unwrap() = None;
+        assert_eq!(
+            fake.drive(&mut machine, Event::Retry).await,
+            enable("default")
+        );
+        assert!(matches!(machine.state(), State::Untrusted(_)));
+        assert_eq!(machine.retry_at(), None);
+    }
+
+    #[tokio::test]
+    async fn retries_back_off() {
+        let fake = Fake::new();
+        let mut machine = Machine::new();
+        let (default, _) = profiles();
+
+        *fake.failing.lock().unwrap() = Some(Subsystem::Wireguard);
+        let event = Event::Network(Target::Untrusted(default));
+        fake.drive(&mut machine, event).await;
+        let first = machine.retry_at().unwrap() - Instant::now();
+
+        fake.drive(&mut machine, Event::Retry).await;
+        let second = machine.retry_at().unwrap() - Instant::now();
+        assert!(second > first + Duration::from_secs(4));
+    }
+
+    #[tokio::test]
+    async fn trusted_after_a_failure_removes_the_kill_switch() {
+        let fake = Fake::new();
+        let mut machine = Machine::new();
+        let (default, _) = profiles();
+
+        *fake.failing.lock().unwrap() = Some(Subsystem::Wireguard);
+        let event = Event::Network(Target::Untrusted(default));
+        fake.drive(&mut machine, event).await;
+
+        // It isn't known what is left, so everything is cleaned up
+        let log = fake
+            .drive(&mut machine, Event::Network(Target::Trusted))
+            .await;
+        assert_eq!(
+            log,
+            [
+                "networkd disable all",
+                "rule disable all",
+                "wireguard disable all",
+                "firewall disable all",
+            ]
+        );
+        assert!(matches!(machine.state(), State::Trusted));
+        assert_eq!(machine.retry_at(), None);
+    }
+
+    #[tokio::test]
+    async fn pause_then_resume() {
+        let fake = Fake::new();
+        let mut machine = Machine::new();
+        let (default, _) = profiles();
+
+        let event = Event::Network(Target::Untrusted(default));
+        fake.drive(&mut machine, event).await;
+
+        let until = Instant::now() + Duration::from_secs(60);
+        let log = fake.drive(&mut machine, Event::Pause(Some(until))).await;
+        assert_eq!(log, disable("default"));
+        assert!(matches!(machine.state(), State::Paused(Some(_))));
+        assert_eq!(machine.resume_at(), Some(until));
+
+        // The network changing doesn't end the pause
+        let log = fake
+            .drive(&mut machine, Event::Network(Target::Trusted))
+            .await;
+        assert!(log.is_empty());
+        let (default, _) = profiles();
+        let event = Event::Network(Target::Untrusted(default));
+        assert!(fake.drive(&mut machine, event).await.is_empty());
+
+        let log = fake.drive(&mut machine, Event::Resume).await;
+        assert_eq!(log, enable("default"));
+        assert!(matches!(machine.state(), State::Untrusted(_)));
+        assert_eq!(machine.resume_at(), None);
+    }
+
+    #[tokio::test]
+    async fn forcing_overrides_trust() {
+        let fake = Fake::new();
+        let mut machine = Machine::new();
+        let (_, work) = profiles();
+
+        // Nothing is known to be set up yet, so everything is cleaned up first
+        let log = fake
+            .drive(&mut machine, Event::Network(Target::Trusted))
+            .await;
+        assert_eq!(log.len(), 4);
+        assert!(matches!(machine.state(), State::Trusted));
+
+        let log = fake.drive(&mut machine, Event::Force(work)).await;
+        assert_eq!(log, enable("work"));
+        assert!(matches!(machine.state(), State::Forced(_)));
+
+        let log = fake
+            .drive(&mut machine, Event::Network(Target::Trusted))
+            .await;
+        assert!(log.is_empty());
+        assert!(matches!(machine.state(), State::Forced(_)));
+
+        let log = fake.drive(&mut machine, Event::Resume).await;
+        assert_eq!(log, disable("work"));
+        assert!(matches!(machine.state(), State::Trusted));
+    }
+
+    #[tokio::test]
+    async fn health_comes_and_goes() {
+        let fake = Fake::new();
+        let mut machine = Machine::new();
+        let (default, work) = profiles();
+
+        let event = Event::Network(Target::Untrusted(default.clone()));
+        fake.drive(&mut machine, event).await;
+
+        // Reports about another interface are stale
+        assert!(fake
+            .drive(&mut machine, health("wg1", false))
+            .await
+            .is_empty());
+        assert!(matches!(machine.state(), State::Untrusted(_)));
+
+        assert!(fake
+            .drive(&mut machine, health("wg0", false))
+            .await
+            .is_empty());
+        assert_eq!(machine.state().kind(), "unhealthy");
+        assert_eq!(machine.state().profile(), Some(&default));
+
+        // Still wanting the same tunnel leaves it alone
+        let event = Event::Network(Target::Untrusted(default));
+        assert!(fake.drive(&mut machine, event).await.is_empty());
+        assert_eq!(machine.state().kind(), "unhealthy");
+
+        assert!(fake
+            .drive(&mut machine, health("wg0", true))
+            .await
+            .is_empty());
+        assert!(matches!(machine.state(), State::Untrusted(_)));
+
+        // Switching profiles starts out healthy
+        fake.drive(&mut machine, health("wg0", false)).await;
+        let log = fake.drive(&mut machine, Event::Force(work)).await;
+        assert_eq!(log, enable("work"));
+        assert!(matches!(machine.state(), State::Forced(_)));
+    }
+
+    #[tokio::test]
+    async fn a_slow_hook_leaves_time_to_tear_down() {
+        let fake = Fake::new();
+        let config = load("\n[hooks]\npre_disable = \"sleep 30\"\n");
+        let default = config.default_profile.clone();
+        let (_, config) = watch::channel(Arc::new(config));
+        let (events, _) = tokio::sync::mpsc::unbounded_channel();
+        let hooks = Hooks::new(crate::links::Links::new(events), config);
+
+        let mut machine = Machine::new();
+        let event = Event::Network(Target::Untrusted(default));
+        fake.drive(&mut machine, event).await;
+        let commands = machine.handle(Event::Shutdown);
+
+        let start = Instant::now();
+        let outcomes = teardown(
+            &fake.subsystems,
+            &hooks,
+            commands,
+            start + Duration::from_secs(2),
+        )
+        .await;
+
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(outcomes.iter().all(|(_, result)| result.is_ok()));
+        assert_eq!(*fake.log.lock().unwrap(), disable("default"));
+    }
+}
@@ -0,0 +1,62 @@
+use anyhow::{Context, Result};
+
+use dbus::arg::PropMap;
+use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
+use dbus::nonblock::{Proxy, SyncConnection};
+use dbus_tokio::connection;
+
+use log::*;
+
+use std::time::Duration;
+
+const SUPPLICANT: &str = "fi.w1.wpa_supplicant1";
+
+fn get_proxy<'a>(conn: &'a SyncConnection, path: dbus::Path<'a>) -> Proxy<'a, &'a SyncConnection> {
+    Proxy::new(SUPPLICANT, path, Duration::from_secs(2), conn)
+}
+
+async fn current_network(conn: &SyncConnection, ifname: &str) -> Result<Option<PropMap>> {
+    let supplicant = get_proxy(conn, dbus::Path::from("/fi/w1/wpa_supplicant1"));
+    let (interface,): (dbus::Path,) = supplicant
+        .method_call(SUPPLICANT, "GetInterface", (ifname,))
+        .await
+        .with_context(|| format!("wpa_supplicant doesn't manage {}", ifname))?;
+
+    let network: dbus::Path = get_proxy(conn, interface)
+        .get("fi.w1.wpa_supplicant1.Interface", "CurrentNetwork")
+        .await
+        .context("failed to get current network")?;
+    if &*network == "/" {
+        return Ok(None);
+    }
+
+    let properties = get_proxy(conn, network)
+        .get("fi.w1.wpa_supplicant1.Network", "Properties")
+        .await
+        .context("failed to get network properties")?;
+
+    Ok(Some(properties))
+}
+
+/// Asks wpa_supplicant for the EAP identity it uses on the current network of `ifname`, which
+/// nl80211 knows nothing about.
+pub async fn eap_identity(ifname: &str) -> Result<Option<String>> {
+    let (resource, conn) = connection::new_system_sync()?;
+    let resource = tokio::spawn(async {
+        let err = resource.await;
+        debug!("lost system dbus connection: {}", err);
+    });
+
+    let network = current_network(&conn, ifname).await;
+    resource.abort();
+
+    // Strings in the network block are returned quoted, like in wpa_supplicant.conf
+    let identity = network?
+        .as_ref()
+        .and_then(|p| p.get("identity"))
+        .and_then(|v| v.0.as_str())
+        .map(|s| s.trim_matches('"').to_string())
+        .filter(|s| !s.is_empty());
+
+    Ok(identity)
+}
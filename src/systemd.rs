@@ -0,0 +1,135 @@
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Duration, Interval, MissedTickBehavior};
+
+use log::*;
+
+use std::ffi::OsString;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram, UnixListener};
+use std::sync::OnceLock;
+
+use super::state::StateRx;
+
+/// The first file descriptor passed by socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Where to send notifications, `None` if systemd isn't listening.
+static NOTIFY_SOCKET: OnceLock<Option<OsString>> = OnceLock::new();
+
+/// Half of `WatchdogSec=`, `None` if the watchdog is off.
+static WATCHDOG: OnceLock<Option<Duration>> = OnceLock::new();
+
+/// Whether systemd started us and waits to hear from us, i.e. `Type=notify`.
+pub fn supervised() -> bool {
+    NOTIFY_SOCKET.get().is_some_and(Option::is_some)
+}
+
+/// Sends `state` to systemd, such as `READY=1`, if it is listening.
+pub fn notify(state: &str) {
+    let Some(Some(path)) = NOTIFY_SOCKET.get() else {
+        return;
+    };
+
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        // A leading @ stands for the abstract namespace
+        let address = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+        socket.send_to_addr(state.as_bytes(), &address)
+    });
+    if let Err(e) = result {
+        debug!("failed to notify systemd: {}", e);
+    }
+}
+
+/// Whether a variable set by systemd is meant for this process rather than a parent.
+fn for_us(pid_var: &str) -> bool {
+    std::env::var(pid_var).is_ok_and(|pid| pid == std::process::id().to_string())
+}
+
+/// Takes what systemd passed in the environment, so that nothing started from here gets it as
+/// well, and returns the control socket if it passed one. Changing the environment is only safe
+/// while there is a single thread, so this has to be called before the runtime is started.
+pub fn take_environment() -> Option<UnixListener> {
+    let _ = NOTIFY_SOCKET.set(std::env::var_os("NOTIFY_SOCKET"));
+
+    let usec = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|&usec| usec > 0);
+    // Without a pid, it is meant for whoever reads it
+    let ours = std::env::var_os("WATCHDOG_PID").is_none() || for_us("WATCHDOG_PID");
+    let _ = WATCHDOG.set(
+        usec.filter(|_| ours)
+            .map(|usec| Duration::from_micros(usec / 2)),
+    );
+
+    let fds = for_us("LISTEN_PID")
+        .then(|| std::env::var("LISTEN_FDS").ok()?.parse::<RawFd>().ok())
+        .flatten();
+
+    for var in [
+        "NOTIFY_SOCKET",
+        "WATCHDOG_USEC",
+        "WATCHDOG_PID",
+        "LISTEN_PID",
+        "LISTEN_FDS",
+        "LISTEN_FDNAMES",
+    ] {
+        std::env::remove_var(var);
+    }
+
+    listener(fds?)
+}
+
+/// The first of the `fds` sockets passed by systemd, which is the control socket.
+fn listener(fds: RawFd) -> Option<UnixListener> {
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + fds {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+
+    if fds > 1 {
+        warn!("got {} sockets from systemd, only using the first", fds);
+    }
+    // systemd hands the descriptors over to us, and nothing else takes this one
+    (fds > 0).then(|| unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) })
+}
+
+/// Ticks at half of `WatchdogSec=`, or never if the watchdog is off.
+pub struct Watchdog(Option<Interval>);
+
+impl Watchdog {
+    pub fn new() -> Self {
+        Watchdog(WATCHDOG.get().copied().flatten().map(|period| {
+            let mut ticks = interval(period);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticks
+        }))
+    }
+
+    pub async fn tick(&mut self) {
+        match &mut self.0 {
+            Some(ticks) => {
+                ticks.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Keeps the status line of the unit up to date with the state.
+pub fn setup_status(mut state: StateRx) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let status = format!("STATUS={}", *state.borrow_and_update());
+            notify(&status);
+
+            if state.changed().await.is_err() {
+                break;
+            }
+        }
+    })
+}
@@ -1,6 +1,7 @@
 use anyhow::Result;
 
 use neli::{
+    attr::AttrHandle,
     consts::{
         nl::{NlmF, NlmFFlags, Nlmsg},
         socket::NlFamily,
@@ -12,16 +13,24 @@ use neli::{
     types::{Buffer, GenlBuffer, NlBuffer},
 };
 
-use tokio::sync::broadcast::Sender;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
 
 use log::*;
 
+use std::collections::{HashMap, HashSet};
 use std::ffi::CStr;
-use std::sync::Arc;
+use std::future::Future;
 
-use super::{Config, Msg};
-use neli_wifi::{Nl80211Attr, Nl80211Cmd, NL_80211_GENL_NAME};
+use super::config::{CaptivePortal, GatewayFingerprint, MacAddr, Trust};
+use super::links::{Description, SharedLinks, Verdict};
+use super::notify::Notice;
+use super::security::{self, Security};
+use super::{fingerprints, gateway, metrics, portal, supplicant};
+use super::{Config, ConfigRx};
+use neli_wifi::{Bss, Nl80211Attr, Nl80211BssStatus, Nl80211Cmd, NL_80211_GENL_NAME};
 
 fn parse_ifindex(bytes: &[u8]) -> u32 {
     let mut num: [u8; 4] = Default::default();
@@ -29,24 +38,33 @@ fn parse_ifindex(bytes: &[u8]) -> u32 {
     u32::from_ne_bytes(num)
 }
 
+/// `seq` is echoed in every reply, which tells them apart from notifications that have 0.
 fn gen_nl80211_header(
     cmd: Nl80211Cmd,
     attrs: GenlBuffer<Nl80211Attr, Buffer>,
     id: u16,
     flags: &[NlmF],
+    seq: u32,
 ) -> Nlmsghdr<u16, Genlmsghdr<Nl80211Cmd, Nl80211Attr>> {
     let genlhdr = Genlmsghdr::new(cmd, 1, attrs);
     Nlmsghdr::new(
         None,
         id,
         NlmFFlags::new(flags),
-        None,
+        Some(seq),
         None,
         NlPayload::Payload(genlhdr),
     )
 }
 
-async fn get_ssid(socket: &mut NlSocket, family: u16, ifindex: u32) -> Result<()> {
+async fn send_ifindex_request(
+    socket: &mut NlSocket,
+    family: u16,
+    ifindex: u32,
+    cmd: Nl80211Cmd,
+    flags: &[NlmF],
+    seq: u32,
+) -> Result<()> {
     let mut attrs = GenlBuffer::new();
     attrs.push(Nlattr::new(
         // nothing is nested
@@ -57,200 +75,631 @@ async fn get_ssid(socket: &mut NlSocket, family: u16, ifindex: u32) -> Result<()
         Buffer::from(ifindex.to_ne_bytes().as_ref()),
     )?);
 
-    let nlhdr = gen_nl80211_header(Nl80211Cmd::CmdGetInterface, attrs, family, &[NlmF::Request]);
+    let nlhdr = gen_nl80211_header(cmd, attrs, family, flags, seq);
     socket.send(&nlhdr).await?;
 
     Ok(())
 }
 
-fn get_ifindex(socket: &mut NlSocketHandle, family: u16, ifname: &str) -> Result<Option<u32>> {
-    let attrs = GenlBuffer::new();
+async fn get_ssid(socket: &mut NlSocket, family: u16, ifindex: u32, seq: u32) -> Result<()> {
+    send_ifindex_request(
+        socket,
+        family,
+        ifindex,
+        Nl80211Cmd::CmdGetInterface,
+        &[NlmF::Request],
+        seq,
+    )
+    .await
+}
+
+/// Dumps the scan results of an interface, which include the access point it is associated with.
+/// That entry carries the BSSID and the advertised security, which are needed before the SSID
+/// can be trusted.
+async fn get_scan(socket: &mut NlSocket, family: u16, ifindex: u32, seq: u32) -> Result<()> {
+    send_ifindex_request(
+        socket,
+        family,
+        ifindex,
+        Nl80211Cmd::CmdGetScan,
+        &[NlmF::Request, NlmF::Dump],
+        seq,
+    )
+    .await
+}
+
+async fn dump_interfaces(socket: &mut NlSocket, family: u16, seq: u32) -> Result<()> {
     let nlhdr = gen_nl80211_header(
         Nl80211Cmd::CmdGetInterface,
-        attrs,
+        GenlBuffer::new(),
         family,
         &[NlmF::Request, NlmF::Dump],
+        seq,
     );
-    socket.send(nlhdr)?;
+    socket.send(&nlhdr).await?;
 
-    let mut check_done = false;
-    let msgs: NlBuffer<u16, Genlmsghdr<Nl80211Cmd, Nl80211Attr>> = socket.recv_all()?;
-    let mut ifindex = None;
+    Ok(())
+}
 
-    for msg in msgs {
-        if msg.nl_flags.contains(&NlmF::Multi) {
-            check_done = true;
-        }
+type Nl80211Attrs<'a> =
+    AttrHandle<'a, GenlBuffer<Nl80211Attr, Buffer>, Nlattr<Nl80211Attr, Buffer>>;
 
-        if let Some(payload) = msg.nl_payload.get_payload() {
-            let attrs = payload.get_attr_handle();
-            if let Some(attr) = attrs.get_attribute(Nl80211Attr::AttrIfname) {
-                let i = CStr::from_bytes_with_nul(attr.nla_payload.as_ref())?.to_string_lossy();
+fn get_attr_ifindex(attrs: &Nl80211Attrs) -> Option<u32> {
+    attrs
+        .get_attribute(Nl80211Attr::AttrIfindex)
+        .map(|attr| parse_ifindex(attr.nla_payload.as_ref()))
+}
 
-                if i == ifname {
-                    ifindex = attrs
-                        .get_attribute(Nl80211Attr::AttrIfindex)
-                        .map(|attr| parse_ifindex(attr.nla_payload.as_ref()));
-                }
-            }
+fn get_attr_bssid(attrs: &Nl80211Attrs) -> Option<MacAddr> {
+    attrs
+        .get_attribute(Nl80211Attr::AttrMac)
+        .and_then(|attr| MacAddr::from_bytes(attr.nla_payload.as_ref()))
+}
+
+fn get_attr_ifname(attrs: &Nl80211Attrs) -> Option<String> {
+    let attr = attrs.get_attribute(Nl80211Attr::AttrIfname)?;
+    CStr::from_bytes_with_nul(attr.nla_payload.as_ref())
+        .ok()
+        .map(|s| s.to_string_lossy().into_owned())
+}
+
+/// How long to wait for the default gateway of a known network before giving up on it.
+const GATEWAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A monitored wireless interface.
+#[derive(Default)]
+struct Link {
+    bssid: Option<MacAddr>,
+    security: Option<Security>,
+    /// The check in flight for the current network, if any.
+    check: Option<Check>,
+}
+
+/// A check of the current network running in the background, aborted once the link changes.
+struct Check {
+    id: u64,
+    handle: JoinHandle<()>,
+}
+
+impl Drop for Check {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// The verdict a check came to.
+struct CheckResult {
+    ifindex: u32,
+    id: u64,
+    verdict: Verdict,
+}
+
+/// Checks the default gateway of a known network against its fingerprint, learning it if allowed.
+async fn verify_gateway(ifindex: u32, ssid: &str, fingerprint: GatewayFingerprint) -> bool {
+    let mac = match gateway::wait_for_gateway_mac(ifindex, GATEWAY_TIMEOUT).await {
+        Ok(Some(mac)) => mac,
+        Ok(None) => {
+            warn!(
+                "no gateway appeared on known network '{}', not trusting",
+                ssid
+            );
+            return false;
+        }
+        Err(e) => {
+            error!("failed to get gateway of known network '{}': {}", ssid, e);
+            return false;
         }
+    };
+
+    let learned = fingerprints::learned(ssid).unwrap_or_else(|e| {
+        error!("failed to read learned gateways: {:#}", e);
+        Vec::new()
+    });
+
+    if fingerprint.gateway_macs.contains(&mac) || learned.contains(&mac) {
+        info!("gateway {} of known network '{}' matches", mac, ssid);
+        return true;
     }
 
-    if check_done {
-        if let Some(done) = socket.recv::<Nlmsg, Buffer>()? {
-            if done.nl_type != Nlmsg::Done {
-                return Err(anyhow::anyhow!("no done message recieved"));
+    if fingerprint.learn && fingerprint.gateway_macs.is_empty() && learned.is_empty() {
+        return match fingerprints::learn(ssid, mac) {
+            Ok(()) => {
+                info!("learned gateway {} of known network '{}'", mac, ssid);
+                true
             }
+            Err(e) => {
+                error!("failed to learn gateway of '{}': {:#}", ssid, e);
+                false
+            }
+        };
+    }
+
+    warn!(
+        "gateway {} of known network '{}' doesn't match, not trusting",
+        mac, ssid
+    );
+    false
+}
+
+/// Probes the network until it gets past the captive portal, which may take until someone logs in.
+async fn wait_for_portal(ifname: &str, ssid: &str, portal: &CaptivePortal) {
+    let mut logged = false;
+    while !portal::probe(portal, ifname).await {
+        if !logged {
+            info!(
+                "'{}' doesn't reach the internet yet, possibly a captive portal, holding back the tunnel",
+                ssid
+            );
+            logged = true;
         }
+        sleep(Duration::from_secs(portal.interval)).await;
     }
 
-    if let Some(i) = ifindex {
-        debug!("got ifindex for {}: {}", ifname, i);
-    } else {
-        debug!("failed to find ifindex to check current network, will get later");
+    if logged {
+        info!("'{}' got past the captive portal", ssid);
     }
+}
 
-    Ok(ifindex)
+/// What is still outstanding from looking up the networks that were already connected to when
+/// starting up, and who to tell once nothing is.
+struct Probing {
+    /// The sequence numbers of the requests that weren't fully answered yet. A dump is done with
+    /// its `NLMSG_DONE`, looking up an SSID with its only reply, which decides the verdict.
+    requests: HashSet<u32>,
+    ready: oneshot::Sender<()>,
 }
 
-async fn cmd_connect(
-    socket: &mut NlSocket,
-    ifindex: &mut Option<u32>,
+struct Monitor {
+    socket: NlSocket,
     family: u16,
-    header: &Genlmsghdr<Nl80211Cmd, Nl80211Attr>,
-) {
-    debug!("interface connect to new network, trying to get ssid");
-    let attrs = header.get_attr_handle();
+    links: SharedLinks,
+    /// Keyed by ifindex.
+    monitored: HashMap<u32, Link>,
+    results: UnboundedSender<CheckResult>,
+    next_check: u64,
+    /// Unknown networks the user should hear about.
+    notices: UnboundedSender<Notice>,
+    probing: Option<Probing>,
+    /// The sequence number of the last request.
+    seq: u32,
+}
 
-    if let Some(attr) = attrs.get_attribute(Nl80211Attr::AttrIfindex) {
-        let current_ifindex = parse_ifindex(attr.nla_payload.as_ref());
-        let ifindex = *ifindex.get_or_insert(current_ifindex);
+impl Monitor {
+    /// A sequence number for a new request, which counts towards probing if that is going on.
+    fn next_seq(&mut self) -> u32 {
+        self.seq = self.seq.checked_add(1).unwrap_or(1);
+        if let Some(probing) = &mut self.probing {
+            probing.requests.insert(self.seq);
+        }
+        self.seq
+    }
+
+    fn set_verdict(&self, ifindex: u32, verdict: Option<Verdict>) {
+        self.links.lock().unwrap().set(ifindex, verdict);
+    }
 
-        if ifindex != current_ifindex {
-            debug!("other interface connect, ignoring");
+    /// Runs `check` for the current network of a link, which replaces the verdict of the link
+    /// once it comes to one.
+    fn start_check<F>(&mut self, ifindex: u32, check: F)
+    where
+        F: Future<Output = Option<Verdict>> + Send + 'static,
+    {
+        let id = self.next_check;
+        self.next_check += 1;
+
+        let results = self.results.clone();
+        let handle = tokio::spawn(async move {
+            if let Some(verdict) = check.await {
+                let _ = results.send(CheckResult {
+                    ifindex,
+                    id,
+                    verdict,
+                });
+            }
+        });
+
+        if let Some(link) = self.monitored.get_mut(&ifindex) {
+            link.check = Some(Check { id, handle });
+        }
+    }
+
+    async fn cmd_connect(&mut self, header: &Genlmsghdr<Nl80211Cmd, Nl80211Attr>) {
+        debug!("interface connect to new network, trying to get ssid");
+        let attrs = header.get_attr_handle();
+
+        let Some(ifindex) = get_attr_ifindex(&attrs) else {
+            warn!("no ifindex for new connection, ignoring");
             return;
+        };
+
+        // The link is dropped again once the reply shows it isn't monitored
+        let link = self.monitored.entry(ifindex).or_default();
+        link.bssid = get_attr_bssid(&attrs);
+        if let Some(bssid) = link.bssid {
+            debug!("associated with access point {}", bssid);
         }
 
-        if let Err(e) = get_ssid(socket, family, ifindex).await {
+        // Our association request shows the security that was picked. Not every driver reports
+        // it, in which case the access point is looked up in the scan results instead.
+        link.security = attrs
+            .get_attribute(Nl80211Attr::AttrReqIe)
+            .map(|attr| security::from_ies(attr.nla_payload.as_ref()));
+
+        let security = link.security;
+        let seq = self.next_seq();
+        let result = match security {
+            Some(_) => get_ssid(&mut self.socket, self.family, ifindex, seq).await,
+            None => get_scan(&mut self.socket, self.family, ifindex, seq).await,
+        };
+        if let Err(e) = result {
             error!("failed to get ssid: {}", e);
+            metrics::error("wifi");
         }
-    } else {
-        warn!("no ifindex for new connection, ignoring");
     }
-}
 
-async fn cmd_new_interface(
-    header: &Genlmsghdr<Nl80211Cmd, Nl80211Attr>,
-    tx: &Sender<Msg>,
-    known_networks: &Vec<String>,
-) {
-    let attrs = header.get_attr_handle();
-    debug!("attempting to get ssid from message");
-    if let Some(attr) = attrs.get_attribute(Nl80211Attr::AttrSsid) {
-        let ssid = String::from_utf8_lossy(attr.nla_payload.as_ref());
-        if known_networks.iter().any(|s| *s == ssid) {
-            info!("connected to known network '{}', disabling", ssid);
-            tx.send(Msg::Disable).unwrap();
-        } else {
-            info!("connected to unknown network '{}', enabling", ssid);
-            tx.send(Msg::Enable).unwrap();
+    fn cmd_disconnect(&mut self, header: &Genlmsghdr<Nl80211Cmd, Nl80211Attr>) {
+        let attrs = header.get_attr_handle();
+
+        if let Some(ifindex) = get_attr_ifindex(&attrs) {
+            if let Some(link) = self.monitored.get_mut(&ifindex) {
+                debug!("interface disconnect from network");
+                *link = Link::default();
+                self.set_verdict(ifindex, None);
+            }
         }
-    } else {
-        debug!("no ssid when there should be one, ignoring");
     }
-}
 
-async fn handle_payload(
-    socket: &mut NlSocket,
-    ifindex: &mut Option<u32>,
-    family: u16,
-    tx: &Sender<Msg>,
-    payload: &Genlmsghdr<Nl80211Cmd, Nl80211Attr>,
-    known_networks: &Vec<String>,
-) {
-    match payload.cmd {
-        Nl80211Cmd::CmdConnect => {
-            cmd_connect(socket, ifindex, family, payload).await;
+    async fn cmd_new_scan_results(&mut self, header: &Genlmsghdr<Nl80211Cmd, Nl80211Attr>) {
+        let attrs = header.get_attr_handle();
+
+        let Some(ifindex) = get_attr_ifindex(&attrs) else {
+            return;
+        };
+        let Ok(bss) = Bss::try_from(attrs) else {
+            return;
+        };
+        // Only the access point the interface is associated with matters
+        if bss.status != Some(u16::from(Nl80211BssStatus::BssStatusAssociated).into()) {
+            return;
         }
 
-        Nl80211Cmd::CmdDisconnect => {
-            debug!("interface disconnect from network");
-            tx.send(Msg::Disable).unwrap();
+        if let Some(link) = self.monitored.get_mut(&ifindex) {
+            link.bssid = bss.bssid.as_deref().and_then(MacAddr::from_bytes);
+            if let Some(bssid) = link.bssid {
+                debug!("associated with access point {}", bssid);
+            }
+            link.security = bss.information_elements.as_deref().map(security::from_ies);
+
+            let seq = self.next_seq();
+            if let Err(e) = get_ssid(&mut self.socket, self.family, ifindex, seq).await {
+                error!("failed to get ssid: {}", e);
+                metrics::error("wifi");
+                self.answered(seq);
+            }
+        }
+    }
+
+    async fn cmd_new_interface(
+        &mut self,
+        header: &Genlmsghdr<Nl80211Cmd, Nl80211Attr>,
+        dump: bool,
+        config: &Config,
+    ) {
+        let attrs = header.get_attr_handle();
+        let (Some(ifindex), Some(ifname)) = (get_attr_ifindex(&attrs), get_attr_ifname(&attrs))
+        else {
+            return;
+        };
+
+        if !config.monitors(&ifname) {
+            if self.monitored.remove(&ifindex).is_some() {
+                self.set_verdict(ifindex, None);
+            }
+            return;
         }
 
-        Nl80211Cmd::CmdNewInterface => {
-            cmd_new_interface(payload, &tx, known_networks).await;
+        debug!("attempting to get ssid for {} from message", ifname);
+        let Some(attr) = attrs.get_attribute(Nl80211Attr::AttrSsid) else {
+            debug!("{} is not connected", ifname);
+            self.monitored.entry(ifindex).or_default();
+            return;
+        };
+
+        // A dump lists every interface, which happens when starting or reloading. The access
+        // point has to be looked up first since there was no connect event to take it from.
+        if dump {
+            self.monitored.entry(ifindex).or_default();
+            let seq = self.next_seq();
+            if let Err(e) = get_scan(&mut self.socket, self.family, ifindex, seq).await {
+                error!("failed to get scan results: {}", e);
+                metrics::error("wifi");
+                self.answered(seq);
+            }
+            return;
         }
-        _ => {}
+
+        let ssid = String::from_utf8_lossy(attr.nla_payload.as_ref()).into_owned();
+        let eap_identity = if config.needs_eap_identity(&ssid) {
+            supplicant::eap_identity(&ifname).await.unwrap_or_else(|e| {
+                error!("failed to get eap identity: {:#}", e);
+                metrics::error("wifi");
+                None
+            })
+        } else {
+            None
+        };
+
+        let link = self.monitored.entry(ifindex).or_default();
+        link.check = None;
+        let security = link.security.unwrap_or_else(|| {
+            warn!(
+                "unable to tell the security of '{}', assuming it is open",
+                ssid
+            );
+            Security::Open
+        });
+        let bssid = link.bssid;
+        let trust = config.trust(&ssid, bssid, security, eap_identity.as_deref());
+        let description = Description {
+            ifname: ifname.clone(),
+            wireless: true,
+            network: Some(ssid.clone()),
+            bssid,
+            decision: trust.to_string(),
+        };
+        let verdict = match trust {
+            Trust::Trusted => {
+                info!("{} connected to known network '{}'", ifname, ssid);
+                Verdict::Trusted
+            }
+            Trust::VerifyGateway(fingerprint) => {
+                // Stay untrusted until the gateway turns out to be the expected one
+                info!(
+                    "{} connected to known network '{}', checking gateway",
+                    ifname, ssid
+                );
+
+                let s = ssid.clone();
+                self.start_check(ifindex, async move {
+                    let trusted = verify_gateway(ifindex, &s, fingerprint).await;
+                    trusted.then_some(Verdict::Trusted)
+                });
+
+                Verdict::Untrusted(config.profile_for(&ssid))
+            }
+            Trust::Insecure => {
+                warn!("known network '{}' is {}, not trusting", ssid, security);
+                Verdict::Untrusted(config.profile_for(&ssid))
+            }
+            Trust::UnexpectedSecurity => {
+                warn!(
+                    "known network '{}' is {} with eap identity {}, which isn't allowed, not trusting",
+                    ssid,
+                    security,
+                    eap_identity.as_deref().unwrap_or("none")
+                );
+                Verdict::Untrusted(config.profile_for(&ssid))
+            }
+            Trust::UnexpectedBssid => {
+                match bssid {
+                    Some(bssid) => warn!(
+                        "known network '{}' served from unexpected access point {}, not trusting",
+                        ssid, bssid
+                    ),
+                    None => warn!(
+                        "unable to verify access point of known network '{}', not trusting",
+                        ssid
+                    ),
+                }
+                Verdict::Untrusted(config.profile_for(&ssid))
+            }
+            Trust::Untrusted => {
+                let profile = config.profile_for(&ssid);
+                info!(
+                    "{} connected to unknown network '{}', using profile '{}'",
+                    ifname, ssid, profile.name
+                );
+                // Only gone when shutting down
+                let _ = self.notices.send(Notice::Untrusted { ssid: ssid.clone() });
+
+                match &config.captive_portal {
+                    // Bringing the tunnel up behind a portal would also send the DNS queries of
+                    // the login page through it, so wait until the network is usable
+                    Some(portal) => {
+                        let portal = portal.clone();
+                        let (i, s) = (ifname.clone(), ssid.clone());
+                        self.start_check(ifindex, async move {
+                            wait_for_portal(&i, &s, &portal).await;
+                            Some(Verdict::Untrusted(profile))
+                        });
+                        Verdict::Portal
+                    }
+                    None => Verdict::Untrusted(profile),
+                }
+            }
+        };
+
+        self.links.lock().unwrap().describe(ifindex, description);
+        self.set_verdict(ifindex, Some(verdict));
     }
-}
 
-async fn handle_messages(
-    socket: &mut NlSocket,
-    ifindex: &mut Option<u32>,
-    family: u16,
-    tx: &Sender<Msg>,
-    messages: NlBuffer<u16, Genlmsghdr<Nl80211Cmd, Nl80211Attr>>,
-    known_networks: &Vec<String>,
-) {
-    for msg in messages {
-        if msg.nl_flags.contains(&NlmF::Request) {
-            continue;
+    async fn handle_payload(
+        &mut self,
+        payload: &Genlmsghdr<Nl80211Cmd, Nl80211Attr>,
+        dump: bool,
+        config: &Config,
+    ) {
+        match payload.cmd {
+            Nl80211Cmd::CmdConnect => {
+                self.cmd_connect(payload).await;
+            }
+
+            Nl80211Cmd::CmdDisconnect => {
+                self.cmd_disconnect(payload);
+            }
+
+            Nl80211Cmd::CmdNewScanResults if dump => {
+                self.cmd_new_scan_results(payload).await;
+            }
+
+            Nl80211Cmd::CmdNewInterface => {
+                self.cmd_new_interface(payload, dump, config).await;
+            }
+
+            Nl80211Cmd::CmdDelInterface => {
+                let attrs = payload.get_attr_handle();
+                if let Some(i) = get_attr_ifindex(&attrs) {
+                    if self.monitored.remove(&i).is_some() {
+                        debug!("monitored interface removed");
+                        self.set_verdict(i, None);
+                    }
+                }
+            }
+            _ => {}
         }
+    }
+
+    async fn handle_messages(
+        &mut self,
+        messages: NlBuffer<u16, Genlmsghdr<Nl80211Cmd, Nl80211Attr>>,
+        config: &Config,
+    ) {
+        for msg in messages {
+            if msg.nl_flags.contains(&NlmF::Request) {
+                continue;
+            }
+            // The end of a dump, or an error instead of the reply
+            if msg.nl_type == u16::from(Nlmsg::Done) || msg.nl_type == u16::from(Nlmsg::Error) {
+                self.answered(msg.nl_seq);
+                continue;
+            }
 
-        if let Some(payload) = msg.nl_payload.get_payload() {
-            handle_payload(socket, ifindex, family, tx, payload, known_networks).await;
+            let dump = msg.nl_flags.contains(&NlmF::Multi);
+            if let Some(payload) = msg.nl_payload.get_payload() {
+                self.handle_payload(payload, dump, config).await;
+            }
+            // By now the links know about the network of a reply to get_ssid
+            if !dump {
+                self.answered(msg.nl_seq);
+            }
         }
     }
-}
 
-async fn recieve_messages(
-    socket: &mut NlSocket,
-    ifindex: &mut Option<u32>,
-    family: u16,
-    tx: &Sender<Msg>,
-    known_networks: &Vec<String>,
-) {
-    let mut buffer = Vec::new();
-
-    while let Ok(msgs) = socket
-        .recv::<u16, Genlmsghdr<Nl80211Cmd, Nl80211Attr>>(&mut buffer)
-        .await
-    {
-        handle_messages(socket, ifindex, family, tx, msgs, known_networks).await;
+    /// Tells whoever waits that the networks were looked up once the request `seq` was the last
+    /// one outstanding. Anything that isn't part of probing is ignored.
+    fn answered(&mut self, seq: u32) {
+        let done = self
+            .probing
+            .as_mut()
+            .is_some_and(|p| p.requests.remove(&seq) && p.requests.is_empty());
+
+        if done {
+            debug!("initial networks probed");
+            if let Some(probing) = self.probing.take() {
+                let _ = probing.ready.send(());
+            }
+        }
+    }
+
+    fn check_done(&mut self, result: CheckResult) {
+        let current = self
+            .monitored
+            .get(&result.ifindex)
+            .and_then(|link| link.check.as_ref())
+            .is_some_and(|check| check.id == result.id);
+
+        // The result is stale if the link changed networks in the meantime
+        if current {
+            self.set_verdict(result.ifindex, Some(result.verdict));
+        }
+    }
+
+    async fn recieve_messages(
+        &mut self,
+        config: &mut ConfigRx,
+        results: &mut UnboundedReceiver<CheckResult>,
+    ) {
+        let mut buffer = Vec::new();
+
+        loop {
+            tokio::select! {
+                Some(result) = results.recv() => {
+                    self.check_done(result);
+                }
+
+                msgs = self.socket.recv::<u16, Genlmsghdr<Nl80211Cmd, Nl80211Attr>>(&mut buffer) => {
+                    let Ok(msgs) = msgs else { break };
+                    let c = config.borrow().clone();
+                    self.handle_messages(msgs, &c).await;
+                }
+
+                changed = config.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+
+                    // The set of monitored interfaces may have changed as well
+                    debug!("config reloaded, checking current networks again");
+                    let seq = self.next_seq();
+                    if let Err(e) = dump_interfaces(&mut self.socket, self.family, seq).await {
+                        error!("failed to get interfaces: {}", e);
+                        metrics::error("wifi");
+                    }
+                }
+            }
+        }
     }
 }
 
-pub fn setup(tx: Sender<Msg>, config: Arc<Config>) -> Result<JoinHandle<()>> {
+/// Monitors the wireless links. The returned receiver is told once the networks that were already
+/// connected to have been looked up and the links were told about them, or dropped if that
+/// failed.
+pub fn setup(
+    links: SharedLinks,
+    mut config: ConfigRx,
+    notices: UnboundedSender<Notice>,
+) -> Result<(JoinHandle<()>, oneshot::Receiver<()>)> {
     let mut handle = NlSocketHandle::connect(NlFamily::Generic, None, &[])?;
 
     let family = handle.resolve_genl_family(NL_80211_GENL_NAME)?;
-    let id = handle.resolve_nl_mcast_group(NL_80211_GENL_NAME, "mlme")?;
-    handle.add_mcast_membership(&[id])?;
-    let mut ifindex = get_ifindex(&mut handle, family, &config.wlan_interface)?;
+    let mlme = handle.resolve_nl_mcast_group(NL_80211_GENL_NAME, "mlme")?;
+    // Interfaces being added and removed, e.g. USB dongles
+    let cfg = handle.resolve_nl_mcast_group(NL_80211_GENL_NAME, "config")?;
+    handle.add_mcast_membership(&[mlme, cfg])?;
 
-    let mut socket = NlSocket::new(handle)?;
+    let socket = NlSocket::new(handle)?;
 
     debug!("got nl80211 multicast notifications");
 
+    let (ready_tx, ready_rx) = oneshot::channel();
     let handle = tokio::spawn(async move {
-        if let Some(i) = ifindex {
-            debug!("attempt to get current ssid");
-
-            if let Err(e) = get_ssid(&mut socket, family, i).await {
-                error!("failed to get ssid: {}", e);
-            }
-
-            recieve_messages(
-                &mut socket,
-                &mut ifindex,
-                family,
-                &tx,
-                &config.known_networks,
-            )
-            .await;
+        let (results_tx, mut results_rx) = unbounded_channel();
+        let mut monitor = Monitor {
+            socket,
+            family,
+            links,
+            monitored: HashMap::new(),
+            results: results_tx,
+            next_check: 0,
+            notices,
+            probing: Some(Probing {
+                requests: HashSet::new(),
+                ready: ready_tx,
+            }),
+            seq: 0,
+        };
+
+        debug!("attempt to get current networks");
+        let seq = monitor.next_seq();
+        if let Err(e) = dump_interfaces(&mut monitor.socket, family, seq).await {
+            error!("failed to get interfaces: {}", e);
+            metrics::error("wifi");
+            // Nothing is going to be probed, which drops the sender
+            monitor.probing = None;
         }
+
+        monitor.recieve_messages(&mut config, &mut results_rx).await;
     });
 
-    Ok(handle)
+    Ok((handle, ready_rx))
 }
@@ -0,0 +1,463 @@
+use anyhow::{Context, Result};
+
+use neli::{
+    attr::AttrHandle,
+    consts::{
+        nl::{NlmF, NlmFFlags},
+        socket::NlFamily,
+    },
+    genl::{Genlmsghdr, Nlattr},
+    nl::{NlPayload, Nlmsghdr},
+    socket::NlSocketHandle,
+    types::{Buffer, GenlBuffer},
+};
+
+use std::ffi::CString;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::time::{Duration, SystemTime};
+
+use super::enums::{WgAllowedIpAttr, WgCmd, WgDeviceAttr, WgPeerAttr};
+
+type Attrs<'a, T> = AttrHandle<'a, GenlBuffer<T, Buffer>, Nlattr<T, Buffer>>;
+
+/// A public key, shown in base64 like `wg` does.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Key(pub [u8; 32]);
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const ALPHABET: &[u8; 64] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+        for chunk in self.0.chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    let c = ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize];
+                    fmt::Write::write_char(f, c.into())?;
+                } else {
+                    f.write_str("=")?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Key({})", self)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AllowedIp {
+    pub address: IpAddr,
+    pub cidr: u8,
+}
+
+impl fmt::Display for AllowedIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.cidr)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub public_key: Key,
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<AllowedIp>,
+    /// `None` if there never was a handshake.
+    pub last_handshake: Option<SystemTime>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// Seconds between keepalives, `None` if they are off.
+    pub persistent_keepalive: Option<u16>,
+}
+
+/// What the kernel knows about a WireGuard interface, without the private and preshared keys.
+#[derive(Clone, Debug)]
+pub struct Device {
+    /// `None` until a private key is set.
+    pub public_key: Option<Key>,
+    pub listen_port: u16,
+    /// 0 if unset.
+    pub fwmark: u32,
+    pub peers: Vec<Peer>,
+}
+
+impl Device {
+    /// The most recent handshake with any peer, `None` if there never was one.
+    pub fn last_handshake(&self) -> Option<SystemTime> {
+        self.peers.iter().filter_map(|p| p.last_handshake).max()
+    }
+}
+
+fn parse_key(payload: &[u8]) -> Result<Key> {
+    Ok(Key(payload
+        .try_into()
+        .context("key of the wrong length")?))
+}
+
+/// Parses a `struct sockaddr_in` or `struct sockaddr_in6`, where only the family is in native
+/// endian.
+fn parse_endpoint(payload: &[u8]) -> Result<Option<SocketAddr>> {
+    let field = |range: std::ops::Range<usize>| payload.get(range).context("short endpoint");
+    let family = u16::from_ne_bytes(field(0..2)?.try_into()?);
+    let port = u16::from_be_bytes(field(2..4)?.try_into()?);
+
+    Ok(match i32::from(family) {
+        libc::AF_INET => {
+            let ip: [u8; 4] = field(4..8)?.try_into()?;
+            Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        }
+        libc::AF_INET6 => {
+            let flowinfo = u32::from_be_bytes(field(4..8)?.try_into()?);
+            let ip: [u8; 16] = field(8..24)?.try_into()?;
+            let scope_id = u32::from_ne_bytes(field(24..28)?.try_into()?);
+            Some(SocketAddrV6::new(Ipv6Addr::from(ip), port, flowinfo, scope_id).into())
+        }
+        // Not set yet, the peer hasn't been heard from
+        _ => None,
+    })
+}
+
+/// Parses a `struct __kernel_timespec`, which is all zeroes for never.
+fn parse_time(payload: &[u8]) -> Result<Option<SystemTime>> {
+    let secs = i64::from_ne_bytes(payload.get(..8).context("short time")?.try_into()?);
+    let nanos = i64::from_ne_bytes(payload.get(8..16).context("short time")?.try_into()?);
+
+    if secs <= 0 {
+        return Ok(None);
+    }
+    let since_epoch = Duration::new(secs as u64, nanos.clamp(0, 999_999_999) as u32);
+    Ok(Some(SystemTime::UNIX_EPOCH + since_epoch))
+}
+
+fn parse_allowed_ip(attrs: &Attrs<WgAllowedIpAttr>) -> Result<AllowedIp> {
+    let address = attrs
+        .get_attribute(WgAllowedIpAttr::AttrIpaddr)
+        .context("allowed ip without an address")?
+        .nla_payload
+        .as_ref();
+    let address = match address.len() {
+        4 => IpAddr::from(<[u8; 4]>::try_from(address)?),
+        16 => IpAddr::from(<[u8; 16]>::try_from(address)?),
+        n => anyhow::bail!("allowed ip address of {} bytes", n),
+    };
+
+    Ok(AllowedIp {
+        address,
+        cidr: attrs.get_attr_payload_as(WgAllowedIpAttr::AttrCidrMask)?,
+    })
+}
+
+fn parse_peer(attrs: &Attrs<WgPeerAttr>) -> Result<Peer> {
+    let payload = |attr| attrs.get_attribute(attr).map(|a| a.nla_payload.as_ref());
+
+    let public_key = parse_key(payload(WgPeerAttr::AttrPublicKey).context("peer without a key")?)?;
+    let endpoint = payload(WgPeerAttr::AttrEndpoint).map(parse_endpoint);
+    let last_handshake = payload(WgPeerAttr::AttrLastHandshakeTime).map(parse_time);
+
+    let mut allowed_ips = Vec::new();
+    if let Some(ips) = attrs.get_attribute(WgPeerAttr::AttrAllowedips) {
+        // A list of nested attributes, typed by their index
+        for ip in ips.get_attr_handle::<u16>()?.iter() {
+            allowed_ips.push(parse_allowed_ip(&ip.get_attr_handle()?)?);
+        }
+    }
+
+    Ok(Peer {
+        public_key,
+        endpoint: endpoint.transpose()?.flatten(),
+        allowed_ips,
+        last_handshake: last_handshake.transpose()?.flatten(),
+        rx_bytes: attrs
+            .get_attr_payload_as(WgPeerAttr::AttrRxBytes)
+            .unwrap_or(0),
+        tx_bytes: attrs
+            .get_attr_payload_as(WgPeerAttr::AttrTxBytes)
+            .unwrap_or(0),
+        persistent_keepalive: attrs
+            .get_attr_payload_as(WgPeerAttr::AttrPersistentKeepaliveInterval)
+            .ok()
+            .filter(|&interval: &u16| interval > 0),
+    })
+}
+
+/// Adds the peers of one message of the dump to `device`. A peer with too many allowed IPs for
+/// one message is continued in the next one, starting with its key again.
+fn add_peers(device: &mut Device, attrs: &Attrs<WgDeviceAttr>) -> Result<()> {
+    let Some(peers) = attrs.get_attribute(WgDeviceAttr::AttrPeers) else {
+        return Ok(());
+    };
+
+    for peer in peers.get_attr_handle::<u16>()?.iter() {
+        let peer = parse_peer(&peer.get_attr_handle()?)?;
+
+        match device.peers.last_mut() {
+            Some(last) if last.public_key == peer.public_key => {
+                last.allowed_ips.extend(peer.allowed_ips)
+            }
+            _ => device.peers.push(peer),
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_device(attrs: &Attrs<WgDeviceAttr>) -> Result<Device> {
+    Ok(Device {
+        public_key: attrs
+            .get_attribute(WgDeviceAttr::AttrPublicKey)
+            .map(|a| parse_key(a.nla_payload.as_ref()))
+            .transpose()?,
+        listen_port: attrs.get_attr_payload_as(WgDeviceAttr::AttrListenPort)?,
+        fwmark: attrs
+            .get_attr_payload_as(WgDeviceAttr::AttrFwmark)
+            .unwrap_or(0),
+        peers: Vec::new(),
+    })
+}
+
+/// Asks the kernel about the WireGuard interface `ifname`.
+pub async fn get_device(ifname: &str) -> Result<Device> {
+    let ifname = CString::new(ifname)?;
+
+    tokio::task::spawn_blocking(move || {
+        let mut socket = NlSocketHandle::connect(NlFamily::Generic, None, &[])?;
+        let family = socket.resolve_genl_family("wireguard")?;
+
+        let mut attrs = GenlBuffer::new();
+        attrs.push(Nlattr::new(
+            // nothing is nested
+            false,
+            // use native endian rather than network order
+            false,
+            WgDeviceAttr::AttrIfname,
+            Buffer::from(ifname.to_bytes_with_nul()),
+        )?);
+
+        let genlheader = Genlmsghdr::new(WgCmd::CmdGetDevice, 1, attrs);
+        let header = Nlmsghdr::new(
+            None,
+            family,
+            NlmFFlags::new(&[NlmF::Request, NlmF::Dump]),
+            None,
+            None,
+            NlPayload::Payload(genlheader),
+        );
+        socket.send(header)?;
+
+        // Devices with many peers are split over several messages. Only the first one describes
+        // the device, the rest only carry more peers.
+        let mut device = None;
+        for msg in socket.iter::<u16, Genlmsghdr<WgCmd, WgDeviceAttr>>(false) {
+            let msg = msg?;
+            let NlPayload::Payload(payload) = &msg.nl_payload else {
+                continue;
+            };
+            let attrs = payload.get_attr_handle();
+
+            let device = match &mut device {
+                Some(device) => device,
+                None => device.insert(parse_device(&attrs)?),
+            };
+            add_peers(device, &attrs)?;
+        }
+
+        device.with_context(|| format!("no wireguard device {}", ifname.to_string_lossy()))
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use neli::consts::genl::NlAttrType;
+
+    const KEY: [u8; 32] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
+        25, 26, 27, 28, 29, 30, 31,
+    ];
+    const OTHER_KEY: [u8; 32] = [0xff; 32];
+
+    fn attr<T: NlAttrType>(kind: T, payload: &[u8]) -> Nlattr<T, Buffer> {
+        Nlattr::new(false, false, kind, Buffer::from(payload)).unwrap()
+    }
+
+    fn nested<T: NlAttrType, U: NlAttrType>(
+        kind: T,
+        children: &[Nlattr<U, Buffer>],
+    ) -> Nlattr<T, Buffer> {
+        let mut attr = Nlattr::new(true, false, kind, Buffer::new()).unwrap();
+        for child in children {
+            attr.add_nested_attribute(child).unwrap();
+        }
+        attr
+    }
+
+    fn sockaddr_in(ip: [u8; 4], port: u16) -> Vec<u8> {
+        let mut addr = (libc::AF_INET as u16).to_ne_bytes().to_vec();
+        addr.extend(port.to_be_bytes());
+        addr.extend(ip);
+        addr.extend([0; 8]);
+        addr
+    }
+
+    fn allowed_ip(index: u16, ip: &[u8], cidr: u8) -> Nlattr<u16, Buffer> {
+        let family = if ip.len() == 4 {
+            libc::AF_INET
+        } else {
+            libc::AF_INET6
+        } as u16;
+        nested(
+            index,
+            &[
+                attr(WgAllowedIpAttr::AttrFamily, &family.to_ne_bytes()),
+                attr(WgAllowedIpAttr::AttrIpaddr, ip),
+                attr(WgAllowedIpAttr::AttrCidrMask, &[cidr]),
+            ],
+        )
+    }
+
+    #[test]
+    fn shows_keys_in_base64() {
+        assert_eq!(
+            Key(KEY).to_string(),
+            "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8="
+        );
+        assert_eq!(
+            Key([0; 32]).to_string(),
+            "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+        );
+        assert_eq!(
+            Key(OTHER_KEY).to_string(),
+            "//////////////////////////////////////////8="
+        );
+        assert!(parse_key(&KEY[..31]).is_err());
+    }
+
+    #[test]
+    fn parses_endpoints() {
+        let endpoint = parse_endpoint(&sockaddr_in([192, 0, 2, 1], 51820)).unwrap();
+        assert_eq!(endpoint, Some("192.0.2.1:51820".parse().unwrap()));
+
+        let mut addr = (libc::AF_INET6 as u16).to_ne_bytes().to_vec();
+        addr.extend(51820u16.to_be_bytes());
+        addr.extend(0u32.to_be_bytes());
+        addr.extend([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        addr.extend(0u32.to_ne_bytes());
+        let endpoint = parse_endpoint(&addr).unwrap();
+        assert_eq!(endpoint, Some("[2001:db8::1]:51820".parse().unwrap()));
+
+        // Unset until the peer was heard from
+        assert_eq!(parse_endpoint(&[0; 16]).unwrap(), None);
+        assert!(parse_endpoint(&addr[..20]).is_err());
+        assert!(parse_endpoint(&[2]).is_err());
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time(&[0; 16]).unwrap(), None);
+
+        let mut time = 1_700_000_000i64.to_ne_bytes().to_vec();
+        time.extend(500_000_000i64.to_ne_bytes());
+        let since_epoch = Duration::from_millis(1_700_000_000_500);
+        assert_eq!(
+            parse_time(&time).unwrap(),
+            Some(SystemTime::UNIX_EPOCH + since_epoch)
+        );
+
+        assert!(parse_time(&time[..12]).is_err());
+    }
+
+    #[test]
+    fn continues_peers_in_the_next_message() {
+        let mut handshake = 1_700_000_000i64.to_ne_bytes().to_vec();
+        handshake.extend(0i64.to_ne_bytes());
+
+        let mut first = GenlBuffer::new();
+        first.push(attr(WgDeviceAttr::AttrPublicKey, &OTHER_KEY));
+        first.push(attr(WgDeviceAttr::AttrListenPort, &51820u16.to_ne_bytes()));
+        first.push(nested(
+            WgDeviceAttr::AttrPeers,
+            &[nested(
+                0u16,
+                &[
+                    attr(WgPeerAttr::AttrPublicKey, &KEY),
+                    attr(
+                        WgPeerAttr::AttrEndpoint,
+                        &sockaddr_in([192, 0, 2, 1], 51820),
+                    ),
+                    attr(WgPeerAttr::AttrLastHandshakeTime, &handshake),
+                    attr(WgPeerAttr::AttrRxBytes, &1024u64.to_ne_bytes()),
+                    attr(WgPeerAttr::AttrTxBytes, &2048u64.to_ne_bytes()),
+                    attr(
+                        WgPeerAttr::AttrPersistentKeepaliveInterval,
+                        &25u16.to_ne_bytes(),
+                    ),
+                    nested(
+                        WgPeerAttr::AttrAllowedips,
+                        &[allowed_ip(0, &[10, 0, 0, 0], 8)],
+                    ),
+                ],
+            )],
+        ));
+
+        // The same peer with the rest of its allowed IPs, then another one
+        let mut second = GenlBuffer::new();
+        second.push(nested(
+            WgDeviceAttr::AttrPeers,
+            &[
+                nested(
+                    0u16,
+                    &[
+                        attr(WgPeerAttr::AttrPublicKey, &KEY),
+                        nested(
+                            WgPeerAttr::AttrAllowedips,
+                            &[allowed_ip(0, &[0xfd; 16], 64)],
+                        ),
+                    ],
+                ),
+                nested(1u16, &[attr(WgPeerAttr::AttrPublicKey, &OTHER_KEY)]),
+            ],
+        ));
+
+        let mut device = parse_device(&first.get_attr_handle()).unwrap();
+        add_peers(&mut device, &first.get_attr_handle()).unwrap();
+        add_peers(&mut device, &second.get_attr_handle()).unwrap();
+
+        assert_eq!(device.public_key, Some(Key(OTHER_KEY)));
+        assert_eq!(device.listen_port, 51820);
+        assert_eq!(device.fwmark, 0);
+        assert_eq!(device.peers.len(), 2);
+
+        let peer = &device.peers[0];
+        assert_eq!(peer.public_key, Key(KEY));
+        assert_eq!(peer.endpoint, Some("192.0.2.1:51820".parse().unwrap()));
+        let ips: Vec<_> = peer.allowed_ips.iter().map(|ip| ip.to_string()).collect();
+        assert_eq!(
+            ips,
+            ["10.0.0.0/8", "fdfd:fdfd:fdfd:fdfd:fdfd:fdfd:fdfd:fdfd/64"]
+        );
+        assert_eq!((peer.rx_bytes, peer.tx_bytes), (1024, 2048));
+        assert_eq!(peer.persistent_keepalive, Some(25));
+        assert_eq!(
+            device.last_handshake(),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+
+        let peer = &device.peers[1];
+        assert_eq!(peer.public_key, Key(OTHER_KEY));
+        assert!(peer.allowed_ips.is_empty());
+        assert_eq!(peer.endpoint, None);
+        assert_eq!(peer.last_handshake, None);
+        assert_eq!(peer.persistent_keepalive, None);
+    }
+}
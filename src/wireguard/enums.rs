@@ -23,3 +23,30 @@ pub enum WgDeviceAttr {
 }
 
 impl NlAttrType for WgDeviceAttr {}
+
+#[neli_enum(serialized_type = "u16")]
+pub enum WgPeerAttr {
+    AttrUnspec = 0,
+    AttrPublicKey = 1,
+    AttrPresharedKey = 2,
+    AttrFlags = 3,
+    AttrEndpoint = 4,
+    AttrPersistentKeepaliveInterval = 5,
+    AttrLastHandshakeTime = 6,
+    AttrRxBytes = 7,
+    AttrTxBytes = 8,
+    AttrAllowedips = 9,
+    AttrProtocolVersion = 10,
+}
+
+impl NlAttrType for WgPeerAttr {}
+
+#[neli_enum(serialized_type = "u16")]
+pub enum WgAllowedIpAttr {
+    AttrUnspec = 0,
+    AttrFamily = 1,
+    AttrIpaddr = 2,
+    AttrCidrMask = 3,
+}
+
+impl NlAttrType for WgAllowedIpAttr {}
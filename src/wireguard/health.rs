@@ -0,0 +1,343 @@
+use anyhow::Result;
+
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
+
+use log::*;
+
+use std::time::SystemTime;
+
+use super::{change_listen_port, get_device};
+use crate::config::Health;
+use crate::metrics;
+use crate::state::Event;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long WireGuard keeps using a session without a new handshake, `REJECT_AFTER_TIME`.
+const SESSION_LIFETIME: Duration = Duration::from_secs(180);
+
+/// The latest handshake and the traffic over every peer.
+#[derive(Clone, Copy, Default)]
+struct Sample {
+    handshake: Option<SystemTime>,
+    rx_bytes: u64,
+    tx_bytes: u64,
+}
+
+impl Sample {
+    async fn take(ifname: &str) -> Result<Sample> {
+        let device = get_device(ifname).await?;
+
+        Ok(Sample {
+            handshake: device.last_handshake(),
+            rx_bytes: device.peers.iter().map(|p| p.rx_bytes).sum(),
+            tx_bytes: device.peers.iter().map(|p| p.tx_bytes).sum(),
+        })
+    }
+
+    /// Whether the other end answered since `before`.
+    fn answered(&self, before: &Sample) -> bool {
+        self.handshake > before.handshake && self.rx_bytes > before.rx_bytes
+    }
+
+    /// Whether traffic is being sent since `before` on a session that expired, which means that
+    /// handshakes are tried and never answered.
+    fn unanswered(&self, before: &Sample) -> bool {
+        let expired = self
+            .handshake
+            .is_none_or(|t| t.elapsed().unwrap_or_default() > SESSION_LIFETIME);
+        expired && self.tx_bytes > before.tx_bytes && self.rx_bytes == before.rx_bytes
+    }
+}
+
+enum Phase {
+    /// Waiting for an answer since `since`, after changing the listen port `attempts` times.
+    Waiting {
+        baseline: Sample,
+        since: Instant,
+        attempts: u32,
+    },
+    /// The other end answered, and `last` was the latest sample.
+    Established { last: Sample },
+}
+
+struct Monitor {
+    ifname: String,
+    config: Health,
+    events: UnboundedSender<Event>,
+    /// What was last reported, a tunnel starts out as healthy.
+    healthy: bool,
+}
+
+impl Monitor {
+    fn report(&mut self, healthy: bool) {
+        if healthy == self.healthy {
+            return;
+        }
+
+        self.healthy = healthy;
+        let _ = self.events.send(Event::Health {
+            interface: self.ifname.clone(),
+            healthy,
+        });
+    }
+
+    /// How long to wait for an answer after changing the port `attempts` times.
+    fn window(&self, attempts: u32) -> Duration {
+        Duration::from_secs(self.config.handshake_timeout) * 2u32.pow(attempts.min(6))
+    }
+
+    /// Moves on from `phase` with a new sample, returning the next phase and whether the listen
+    /// port should be changed.
+    fn check(&mut self, phase: Phase, sample: Sample) -> (Phase, bool) {
+        match phase {
+            Phase::Waiting {
+                baseline,
+                since,
+                attempts,
+            } => {
+                if sample.answered(&baseline) {
+                    if !self.healthy {
+                        info!("{} got a handshake again", self.ifname);
+                    }
+                    self.report(true);
+                    return (Phase::Established { last: sample }, false);
+                }
+                if since.elapsed() < self.window(attempts) {
+                    let phase = Phase::Waiting {
+                        baseline,
+                        since,
+                        attempts,
+                    };
+                    return (phase, false);
+                }
+
+                self.report(false);
+                if attempts >= self.config.max_attempts {
+                    // Keep waiting in case it comes back by itself, but leave the port alone
+                    if attempts == self.config.max_attempts {
+                        error!(
+                            "{} got no handshake after changing the listen port {} times, giving up",
+                            self.ifname, attempts
+                        );
+                    }
+                    let phase = Phase::Waiting {
+                        baseline,
+                        since: Instant::now(),
+                        attempts: attempts + 1,
+                    };
+                    return (phase, false);
+                }
+
+                warn!(
+                    "{} got no handshake in {}s, changing the listen port",
+                    self.ifname,
+                    self.window(attempts).as_secs()
+                );
+                let phase = Phase::Waiting {
+                    baseline: sample,
+                    since: Instant::now(),
+                    attempts: attempts + 1,
+                };
+                (phase, true)
+            }
+            Phase::Established { last } => {
+                if sample.unanswered(&last) {
+                    warn!("{} stopped getting handshakes", self.ifname);
+                    let phase = Phase::Waiting {
+                        baseline: sample,
+                        since: Instant::now(),
+                        attempts: 0,
+                    };
+                    return (phase, false);
+                }
+                (Phase::Established { last: sample }, false)
+            }
+        }
+    }
+}
+
+/// Watches `ifname` once the tunnel is up, changing the listen port and reporting on `events`
+/// whenever it doesn't get a handshake in time. `fresh` is whether the tunnel was just brought up
+/// rather than kept from before, in which case it has yet to get its first handshake. Nothing is
+/// watched if `handshake_timeout` is 0.
+pub fn watch(
+    ifname: String,
+    fresh: bool,
+    config: Health,
+    events: UnboundedSender<Event>,
+) -> Option<JoinHandle<()>> {
+    if config.handshake_timeout == 0 {
+        return None;
+    }
+
+    Some(tokio::spawn(async move {
+        let first = match Sample::take(&ifname).await {
+            Ok(sample) => sample,
+            Err(e) => {
+                debug!("failed to get handshakes of {}: {:#}", ifname, e);
+                Sample::default()
+            }
+        };
+        let mut phase = match fresh {
+            true => Phase::Waiting {
+                baseline: first,
+                since: Instant::now(),
+                attempts: 0,
+            },
+            false => Phase::Established { last: first },
+        };
+
+        let mut monitor = Monitor {
+            ifname,
+            config,
+            events,
+            healthy: true,
+        };
+        loop {
+            sleep(POLL_INTERVAL).await;
+
+            let sample = match Sample::take(&monitor.ifname).await {
+                Ok(sample) => sample,
+                Err(e) => {
+                    debug!("failed to get handshakes of {}: {:#}", monitor.ifname, e);
+                    metrics::error("wireguard");
+                    continue;
+                }
+            };
+
+            let (next, reroll) = monitor.check(phase, sample);
+            phase = next;
+            if reroll {
+                if let Err(e) = change_listen_port(&monitor.ifname).await {
+                    error!("failed to change wireguard listen port: {:#}", e);
+                    metrics::error("wireguard");
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    fn monitor(healthy: bool) -> (Monitor, UnboundedReceiver<Event>) {
+        let (events, rx) = unbounded_channel();
+        let monitor = Monitor {
+            ifname: String::from("wg0"),
+            config: Health {
+                handshake_timeout: 30,
+                max_attempts: 2,
+            },
+            events,
+            healthy,
+        };
+        (monitor, rx)
+    }
+
+    /// A sample with a handshake `age` seconds ago.
+    fn sample(age: Option<u64>, rx_bytes: u64, tx_bytes: u64) -> Sample {
+        Sample {
+            handshake: age.map(|age| SystemTime::now() - Duration::from_secs(age)),
+            rx_bytes,
+            tx_bytes,
+        }
+    }
+
+    fn waiting(baseline: Sample, waited: u64, attempts: u32) -> Phase {
+        Phase::Waiting {
+            baseline,
+            since: Instant::now() - Duration::from_secs(waited),
+            attempts,
+        }
+    }
+
+    fn attempts(phase: &Phase) -> Option<u32> {
+        match phase {
+            Phase::Waiting { attempts, .. } => Some(*attempts),
+            Phase::Established { .. } => None,
+        }
+    }
+
+    fn reported(events: &mut UnboundedReceiver<Event>) -> Vec<bool> {
+        std::iter::from_fn(|| match events.try_recv().ok()? {
+            Event::Health { interface, healthy } => {
+                assert_eq!(interface, "wg0");
+                Some(healthy)
+            }
+            _ => panic!("not a health event"),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn notices_a_stale_handshake() {
+        let (mut monitor, mut events) = monitor(true);
+
+        // Sending on a session that is still fresh is fine
+        let last = sample(Some(60), 100, 100);
+        let phase = Phase::Established { last };
+        let (phase, reroll) = monitor.check(phase, sample(Some(60), 100, 200));
+        assert_eq!(attempts(&phase), None);
+        assert!(!reroll);
+
+        // Once it expired nothing coming back means handshakes aren't answered
+        let last = sample(Some(200), 100, 100);
+        let phase = Phase::Established { last };
+        let (phase, reroll) = monitor.check(phase, sample(Some(200), 100, 200));
+        assert_eq!(attempts(&phase), Some(0));
+        assert!(!reroll);
+
+        // Not reported until the window ran out
+        assert!(reported(&mut events).is_empty());
+    }
+
+    #[test]
+    fn changes_the_port_with_backoff() {
+        let (mut monitor, mut events) = monitor(true);
+        let baseline = sample(None, 0, 100);
+
+        let (phase, reroll) = monitor.check(waiting(baseline, 10, 0), sample(None, 0, 200));
+        assert_eq!(attempts(&phase), Some(0));
+        assert!(!reroll);
+
+        let (phase, reroll) = monitor.check(waiting(baseline, 31, 0), sample(None, 0, 200));
+        assert_eq!(attempts(&phase), Some(1));
+        assert!(reroll);
+        assert_eq!(reported(&mut events), [false]);
+
+        // Twice as long after the first change
+        let (phase, reroll) = monitor.check(waiting(baseline, 31, 1), sample(None, 0, 300));
+        assert_eq!(attempts(&phase), Some(1));
+        assert!(!reroll);
+        let (phase, reroll) = monitor.check(waiting(baseline, 61, 1), sample(None, 0, 300));
+        assert_eq!(attempts(&phase), Some(2));
+        assert!(reroll);
+        assert!(reported(&mut events).is_empty());
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let (mut monitor, mut events) = monitor(false);
+        let baseline = sample(None, 0, 100);
+
+        let (phase, reroll) = monitor.check(waiting(baseline, 121, 2), sample(None, 0, 200));
+        assert_eq!(attempts(&phase), Some(3));
+        assert!(!reroll);
+        let (phase, reroll) = monitor.check(waiting(baseline, 241, 3), sample(None, 0, 300));
+        assert_eq!(attempts(&phase), Some(4));
+        assert!(!reroll);
+        assert!(reported(&mut events).is_empty());
+
+        // It can still come back by itself
+        let (phase, reroll) = monitor.check(waiting(baseline, 0, 4), sample(Some(1), 50, 400));
+        assert_eq!(attempts(&phase), None);
+        assert!(!reroll);
+        assert_eq!(reported(&mut events), [true]);
+    }
+}
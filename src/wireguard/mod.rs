@@ -1,8 +1,9 @@
-use super::{Config, Msg};
+use super::state::{Command, Event, Request};
+use super::ConfigRx;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-use tokio::sync::broadcast::Receiver;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio::task::JoinHandle;
 
 use neli::{
@@ -17,12 +18,14 @@ use neli::{
 };
 
 use std::ffi::CString;
-use std::sync::Arc;
 
 use log::*;
 
+mod device;
 mod enums;
+mod health;
 
+pub use device::{get_device, Device};
 use enums::{WgCmd, WgDeviceAttr};
 
 async fn change_listen_port(ifname: &str) -> Result<()> {
@@ -54,13 +57,15 @@ async fn change_listen_port(ifname: &str) -> Result<()> {
         let header = Nlmsghdr::new(
             None,
             family,
-            NlmFFlags::new(&[NlmF::Request]),
+            NlmFFlags::new(&[NlmF::Request, NlmF::Ack]),
             None,
             None,
             NlPayload::Payload(genlheader),
         );
 
         socket.send(header)?;
+        // Wait for the ack, which is where an error would show up
+        socket.recv::<u16, Buffer>()?;
 
         debug!("changed wireguard listen port");
 
@@ -69,16 +74,55 @@ async fn change_listen_port(ifname: &str) -> Result<()> {
     .await?
 }
 
-pub fn setup(mut rx: Receiver<Msg>, config: Arc<Config>) -> JoinHandle<()> {
+/// Changes the listen port when the tunnel is enabled, and watches it for handshakes until it is
+/// disabled, see [`health::watch`].
+pub fn setup(
+    mut rx: UnboundedReceiver<Request>,
+    config: ConfigRx,
+    events: UnboundedSender<Event>,
+) -> JoinHandle<()> {
     tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            if msg == Msg::Enable {
-                // Some networks have odd NAT and firewalls which means that the last used port is
-                // likely not usable. Change the port once to improve the odds.
-                if let Err(e) = change_listen_port(&config.wireguard_interface).await {
-                    error!("failed to change wireguard listen port: {}", e);
+        let mut monitor: Option<JoinHandle<()>> = None;
+
+        while let Some((command, ack)) = rx.recv().await {
+            let mut result = Ok(());
+
+            match command {
+                Command::Enable { old, new } => {
+                    if let Some(monitor) = monitor.take() {
+                        monitor.abort();
+                    }
+
+                    // Don't disturb a tunnel that is already up, e.g. after a config reload
+                    let fresh =
+                        old.is_none_or(|old| old.wireguard_interface != new.wireguard_interface);
+                    if fresh {
+                        // Some networks have odd NAT and firewalls which means that the last used
+                        // port is likely not usable. Change the port once to improve the odds.
+                        result = change_listen_port(&new.wireguard_interface)
+                            .await
+                            .context("failed to change wireguard listen port");
+                    }
+
+                    if result.is_ok() {
+                        let health = config.borrow().health.clone();
+                        let ifname = new.wireguard_interface.clone();
+                        monitor = health::watch(ifname, fresh, health, events.clone());
+                    }
                 }
+                Command::Disable(_) => {
+                    if let Some(monitor) = monitor.take() {
+                        monitor.abort();
+                    }
+                }
+                Command::Reconcile(_) => {}
             }
+
+            let _ = ack.send(result);
+        }
+
+        if let Some(monitor) = monitor {
+            monitor.abort();
         }
     })
 }